@@ -0,0 +1,153 @@
+extern crate hound;
+
+mod renderer;
+mod preview;
+
+pub use self::renderer::*;
+pub use self::preview::*;
+
+use std::path::Path;
+
+#[derive(Copy, Clone, Debug)]
+pub enum AudioError {
+    CouldntOpenFile,
+    UnsupportedFormat,
+    ErrorReadingFile,
+    ErrorWritingFile,
+}
+
+//Interleaved stereo samples in the -1.0 .. 1.0 range
+#[derive(Clone, Debug)]
+pub struct SampleBuffer {
+    pub sample_rate: u32,
+    pub samples: Vec<f32>,
+}
+
+impl SampleBuffer {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            sample_rate,
+            samples: Vec::new(),
+        }
+    }
+    pub fn frame_count(&self) -> usize {
+        self.samples.len() / 2
+    }
+    pub fn frame_at(&self, time: f64) -> usize {
+        (time * self.sample_rate as f64).round().max(0.0) as usize
+    }
+    //Adds `other` on top of this buffer starting at `frame`, growing the buffer if needed
+    pub fn mix(&mut self, other: &SampleBuffer, frame: usize, gain: f32) {
+        let end = (frame + other.frame_count()) * 2;
+        if self.samples.len() < end {
+            self.samples.resize(end, 0.0);
+        }
+        for (dst, src) in self.samples[frame * 2 .. end].iter_mut().zip(other.samples.iter()) {
+            *dst += src * gain;
+        }
+    }
+    pub fn skip_frames(&self, frames: usize) -> SampleBuffer {
+        let beg = (frames * 2).min(self.samples.len());
+        SampleBuffer {
+            sample_rate: self.sample_rate,
+            samples: self.samples[beg ..].to_vec(),
+        }
+    }
+    //Truncates or pads the buffer with silence to exactly `frames` frames
+    pub fn set_frame_count(&mut self, frames: usize) {
+        self.samples.resize(frames * 2, 0.0);
+    }
+    //Linear fade-in at the start and fade-out at the end of the buffer
+    pub fn apply_fades(&mut self, fade_in_frames: usize, fade_out_frames: usize) {
+        let frames = self.frame_count();
+        for i in 0 .. frames {
+            let mut gain = 1.0;
+            if i < fade_in_frames {
+                gain *= i as f32 / fade_in_frames as f32;
+            }
+            if frames - i <= fade_out_frames {
+                gain *= (frames - i - 1) as f32 / fade_out_frames as f32;
+            }
+            self.samples[i * 2] *= gain;
+            self.samples[i * 2 + 1] *= gain;
+        }
+    }
+    pub fn write_wav(&self, path: &Path) -> Result<(), AudioError> {
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: self.sample_rate,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(path, spec)
+            .map_err(|_| AudioError::ErrorWritingFile)?;
+        for sample in &self.samples {
+            let v = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            writer.write_sample(v).map_err(|_| AudioError::ErrorWritingFile)?;
+        }
+        writer.finalize().map_err(|_| AudioError::ErrorWritingFile)
+    }
+}
+
+//Decodes a PCM WAV file and converts it to stereo at the requested sample rate
+pub fn load_wav(path: &Path, sample_rate: u32) -> Result<SampleBuffer, AudioError> {
+    let mut reader = hound::WavReader::open(path).map_err(|e| match e {
+        hound::Error::IoError(_) => AudioError::CouldntOpenFile,
+        _ => AudioError::UnsupportedFormat,
+    })?;
+    let spec = reader.spec();
+    if spec.channels == 0 { return Err(AudioError::UnsupportedFormat); }
+    let raw: Vec<f32> = match spec.sample_format {
+        hound::SampleFormat::Float => reader.samples::<f32>()
+            .collect::<Result<_, _>>()
+            .map_err(|_| AudioError::ErrorReadingFile)?,
+        hound::SampleFormat::Int => {
+            let scale = (1i64 << (spec.bits_per_sample - 1)) as f32;
+            reader.samples::<i32>()
+                .map(|s| s.map(|s| s as f32 / scale))
+                .collect::<Result<_, _>>()
+                .map_err(|_| AudioError::ErrorReadingFile)?
+        },
+    };
+    let channels = spec.channels as usize;
+    let mut stereo = Vec::with_capacity(raw.len() / channels * 2);
+    for frame in raw.chunks_exact(channels) {
+        stereo.push(frame[0]);
+        stereo.push(if channels > 1 { frame[1] } else { frame[0] });
+    }
+    Ok(resample(&SampleBuffer { sample_rate: spec.sample_rate, samples: stereo }, sample_rate))
+}
+
+//Linear interpolation is good enough for keysounds
+pub fn resample(buffer: &SampleBuffer, sample_rate: u32) -> SampleBuffer {
+    if buffer.sample_rate == sample_rate {
+        return buffer.clone();
+    }
+    let ratio = buffer.sample_rate as f64 / sample_rate as f64;
+    let src_frames = buffer.frame_count();
+    let dst_frames = (src_frames as f64 / ratio).floor() as usize;
+    let mut samples = Vec::with_capacity(dst_frames * 2);
+    for i in 0 .. dst_frames {
+        let pos = i as f64 * ratio;
+        let idx = pos.floor() as usize;
+        let fract = (pos - idx as f64) as f32;
+        let next = (idx + 1).min(src_frames - 1);
+        for ch in 0 .. 2 {
+            let a = buffer.samples[idx * 2 + ch];
+            let b = buffer.samples[next * 2 + ch];
+            samples.push(a + (b - a) * fract);
+        }
+    }
+    SampleBuffer { sample_rate, samples }
+}
+
+//Loads every keysound of a resource table, paths are relative to `base_dir`.
+//Missing or undecodable files are left as None, like most players do.
+pub fn load_keysounds(resource_table: &[String], base_dir: &Path, sample_rate: u32) -> Vec<Option<SampleBuffer>> {
+    resource_table.iter()
+        .map(|path| {
+            if path.is_empty() { return None; }
+            load_wav(&base_dir.join(path.trim().replace('\\', "/")), sample_rate).ok()
+        })
+        .collect()
+}
@@ -0,0 +1,124 @@
+use super::*;
+
+use crate::bms::{BMSTimings, BMSStops};
+use crate::cbms::{CBMS, ChannelKind, TimedCommand, autoplay_commands};
+use crate::compiler::ImportedBMS;
+
+use std::str::FromStr;
+
+#[derive(Copy, Clone, Debug)]
+pub struct PreviewSettings {
+    pub sample_rate: u32,
+    pub duration: f64,
+    pub fade_in: f64,
+    pub fade_out: f64,
+}
+
+impl Default for PreviewSettings {
+    fn default() -> Self {
+        Self {
+            sample_rate: 44100,
+            duration: 15.0,
+            fade_in: 1.0,
+            fade_out: 2.0,
+        }
+    }
+}
+
+//Start of the `duration` long window holding the most notes. The window is moved
+//back by `lead_in` so the first notes aren't swallowed by the fade-in.
+pub fn densest_region(commands: &[TimedCommand], duration: f64, lead_in: f64) -> f64 {
+    let times: Vec<f64> = commands.iter()
+        .filter(|tc| tc.command.kind() != ChannelKind::Bgm)
+        .map(|tc| tc.time)
+        .collect();
+    let (mut best_start, mut best_cnt) = (0.0, 0);
+    let mut end = 0;
+    for (beg, &start) in times.iter().enumerate() {
+        while end < times.len() && times[end] < start + duration {
+            end += 1;
+        }
+        if end - beg > best_cnt {
+            best_cnt = end - beg;
+            best_start = start;
+        }
+    }
+    (best_start - lead_in).max(0.0)
+}
+
+//A numeric #PREVIEW is taken as the start in seconds, anything else falls back to the densest section
+pub fn preview_start(commands: &[TimedCommand], preview: Option<&str>, settings: &PreviewSettings) -> f64 {
+    match preview.and_then(|p| f64::from_str(p).ok()) {
+        Some(start) => start.max(0.0),
+        None => densest_region(commands, settings.duration, settings.fade_in),
+    }
+}
+
+//Mixes every keysound that can be heard inside the window, including ones triggered before it
+pub fn mix_window(commands: &[TimedCommand], keysounds: &[Option<SampleBuffer>], volwav: u32, start: f64, settings: &PreviewSettings) -> SampleBuffer {
+    let gain = volwav as f32 / 100.0;
+    let end = start + settings.duration;
+    let mut output = SampleBuffer::new(settings.sample_rate);
+    for tc in commands.iter().take_while(|tc| tc.time < end) {
+        if let Some(Some(keysound)) = keysounds.get(tc.command.value as usize) {
+            let offset = ((tc.time - start) * settings.sample_rate as f64).round() as isize;
+            if offset >= 0 {
+                output.mix(keysound, offset as usize, gain);
+            } else if ((-offset) as usize) < keysound.frame_count() {
+                output.mix(&keysound.skip_frames((-offset) as usize), 0, gain);
+            }
+        }
+    }
+    output
+}
+
+pub fn render_preview(cbms: &CBMS, timings: &BMSTimings, stops: &BMSStops, keysounds: &[Option<SampleBuffer>], volwav: u32, preview: Option<&str>, settings: &PreviewSettings) -> SampleBuffer {
+    let commands = autoplay_commands(&cbms.timed_commands(timings, stops));
+    let start = preview_start(&commands, preview, settings);
+    let mut output = mix_window(&commands, keysounds, volwav, start, settings);
+    finish_preview(&mut output, settings);
+    output
+}
+
+fn finish_preview(buffer: &mut SampleBuffer, settings: &PreviewSettings) {
+    buffer.set_frame_count(buffer.frame_at(settings.duration));
+    let (fade_in, fade_out) = (buffer.frame_at(settings.fade_in), buffer.frame_at(settings.fade_out));
+    buffer.apply_fades(fade_in, fade_out);
+}
+
+//Writes a preview clip for `bms`. If #PREVIEW names an audio file that can be
+//decoded, its beginning is used instead of mixing keysounds.
+pub fn make_preview(bms: &ImportedBMS, base_dir: &Path, out_path: &Path, settings: &PreviewSettings) -> Result<(), AudioError> {
+    if let Some(preview) = bms.preview.as_ref().filter(|p| f64::from_str(p).is_err()) {
+        if let Ok(mut output) = load_wav(&base_dir.join(preview.replace('\\', "/")), settings.sample_rate) {
+            finish_preview(&mut output, settings);
+            return output.write_wav(out_path);
+        }
+    }
+    let cbms = bms.eval_and_compile();
+    let keysounds = load_keysounds(&bms.resource_table, base_dir, settings.sample_rate);
+    render_preview(&cbms, &bms.timing, &bms.stops, &keysounds, bms.volwav, bms.preview.as_deref(), settings)
+        .write_wav(out_path)
+}
+
+#[cfg(test)]
+#[test]
+fn test_densest_region() {
+    //One measure per second, the third measure is the busiest one
+    let bms = crate::compiler::import_bms("#BPM 240\n#00011:01\n#00111:0101\n#00211:01010101\n#00301:01").unwrap();
+    let timed = bms.eval_and_compile().timed_commands(&bms.timing, &bms.stops);
+    assert_eq!(densest_region(&timed, 1.0, 0.0), 2.0);
+    assert_eq!(densest_region(&timed, 1.0, 0.5), 1.5);
+}
+
+#[cfg(test)]
+#[test]
+fn test_render_preview_uses_numeric_preview() {
+    let bms = crate::compiler::import_bms("#BPM 240\n#PREVIEW 1.5\n#00011:01\n#00211:01").unwrap();
+    let keysounds = vec![None, Some(SampleBuffer { sample_rate: 10, samples: vec![1.0; 20] })];
+    let settings = PreviewSettings { sample_rate: 10, duration: 2.0, fade_in: 0.0, fade_out: 0.0 };
+    let output = render_preview(&bms.eval_and_compile(), &bms.timing, &bms.stops, &keysounds, 100, bms.preview.as_deref(), &settings);
+    assert_eq!(output.frame_count(), 20);
+    assert_eq!(output.samples[4 * 2], 0.0);
+    assert_eq!(output.samples[5 * 2], 1.0);
+}
@@ -0,0 +1,70 @@
+use super::*;
+
+use crate::bms::{BMSTimings, BMSStops};
+use crate::cbms::{CBMS, TimedCommand, autoplay_commands};
+use crate::compiler::ImportedBMS;
+
+#[derive(Copy, Clone, Debug)]
+pub struct RenderSettings {
+    pub sample_rate: u32,
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        Self {
+            sample_rate: 44100,
+        }
+    }
+}
+
+//Mixes the keysounds of `commands` into one buffer, `keysounds` is indexed by keysound id
+pub fn mix_keysounds(commands: &[TimedCommand], keysounds: &[Option<SampleBuffer>], volwav: u32, sample_rate: u32) -> SampleBuffer {
+    let gain = volwav as f32 / 100.0;
+    let mut output = SampleBuffer::new(sample_rate);
+    for tc in commands {
+        if let Some(Some(keysound)) = keysounds.get(tc.command.value as usize) {
+            let frame = output.frame_at(tc.time);
+            output.mix(keysound, frame, gain);
+        }
+    }
+    output
+}
+
+pub fn render_cbms(cbms: &CBMS, timings: &BMSTimings, stops: &BMSStops, keysounds: &[Option<SampleBuffer>], volwav: u32, settings: &RenderSettings) -> SampleBuffer {
+    let commands = autoplay_commands(&cbms.timed_commands(timings, stops));
+    mix_keysounds(&commands, keysounds, volwav, settings.sample_rate)
+}
+
+//Renders the whole chart with autoplay, keysound paths are resolved against `base_dir`
+pub fn render_bms_to_wav(bms: &ImportedBMS, base_dir: &Path, out_path: &Path, settings: &RenderSettings) -> Result<(), AudioError> {
+    let cbms = bms.eval_and_compile();
+    let keysounds = load_keysounds(&bms.resource_table, base_dir, settings.sample_rate);
+    render_cbms(&cbms, &bms.timing, &bms.stops, &keysounds, bms.volwav, settings).write_wav(out_path)
+}
+
+#[cfg(test)]
+fn test_click(sample_rate: u32) -> Option<SampleBuffer> {
+    Some(SampleBuffer { sample_rate, samples: vec![0.5; 8] })
+}
+
+#[cfg(test)]
+#[test]
+fn test_render_cbms_places_keysounds() {
+    //At 240 BPM a 4/4 measure lasts exactly one second
+    let bms = crate::compiler::import_bms("#BPM 240\n#VOLWAV 50\n#00101:0002\n#00111:01000000").unwrap();
+    let keysounds = vec![None, test_click(100), test_click(100)];
+    let output = render_cbms(&bms.eval_and_compile(), &bms.timing, &bms.stops, &keysounds, bms.volwav, &RenderSettings { sample_rate: 100 });
+    assert_eq!(output.frame_count(), 154);
+    assert_eq!(output.samples[100 * 2], 0.25);
+    assert_eq!(output.samples[150 * 2], 0.25);
+    assert_eq!(output.samples[149 * 2], 0.0);
+}
+
+#[cfg(test)]
+#[test]
+fn test_autoplay_commands_skip_ln_ends_and_invisible() {
+    let bms = crate::compiler::import_bms("#BPM 240\n#00151:0102\n#00131:03\n#00211:04").unwrap();
+    let timed = bms.eval_and_compile().timed_commands(&bms.timing, &bms.stops);
+    let values: Vec<u32> = autoplay_commands(&timed).iter().map(|tc| tc.command.value).collect();
+    assert_eq!(values, vec![1, 4]);
+}
@@ -3,8 +3,11 @@ pub struct BMSTime(f64);
 
 //This should be fixed!
 //BPM can change not only per-bar!
-//(bar number, bpm, beats per bar - usually 4 which would imply 4/4 meter), elapsed tim
-pub type BMSTimings = Vec<(BMSTime, f32, usize)>;
+//(bar number, bpm, beats per bar - usually 4 which would imply 4/4 meter, fractional
+//for measures that aren't a whole number of beats), elapsed tim
+pub type BMSTimings = Vec<(BMSTime, f32, f64)>;
+//(stop position, stop duration in seconds), sorted by position
+pub type BMSStops = Vec<(BMSTime, f64)>;
 
 impl BMSTime {
     pub fn from_absolute_time(mut atime: f64, timings: &BMSTimings) -> BMSTime {
@@ -14,14 +17,14 @@ impl BMSTime {
             let (time, bpm, beatsno) = timings[idx];
             let (section_time, section_length) = if idx + 1 < timings.len() {
                 let section_length = timings[idx + 1].0 - time;
-                (section_length.0 * beatsno as f64 * 60.0 / bpm as f64, section_length)
+                (section_length.0 * beatsno * 60.0 / bpm as f64, section_length)
             } else { (-1.0, BMSTime(0.0)) };
             if atime > section_time && section_time > 0.0 {
                 atime -= section_time;
                 ctime += section_length;
             } else {
                 let beats = atime * bpm as f64 / 60.0;
-                ctime += BMSTime(beats / beatsno as f64);
+                ctime += BMSTime(beats / beatsno);
                 break;
             }
             idx += 1;
@@ -42,13 +45,13 @@ impl BMSTime {
             let (bar, bpm, beatsno) = timings[idx];
             let (section_time, section_length) = if idx + 1 < timings.len() {
                 let section_length = timings[idx + 1].0 - bar;
-                (section_length.0 * beatsno as f64 * 60.0 / bpm as f64, section_length)
+                (section_length.0 * beatsno * 60.0 / bpm as f64, section_length)
             } else { (-1.0, BMSTime(0.0)) };
             if section_length < cbar && section_time > 0.0 {
                 cbar -= section_length;
                 ctime += section_time;
             } else {
-                let t = cbar.0 * beatsno as f64 * 60.0 / bpm as f64;
+                let t = cbar.0 * beatsno * 60.0 / bpm as f64;
                 ctime += t;
                 break;
             }
@@ -64,6 +67,23 @@ impl BMSTime {
     pub fn to_absolute_time(&self, timings: &BMSTimings, hint: Option<BMSAbsoluteTimingHint>) -> f64 {
         self.to_absolute_time_and_hint(timings, hint).0
     }
+    //Stops at the very same position as the object don't delay it, only the ones before do
+    pub fn to_absolute_time_with_stops(&self, timings: &BMSTimings, stops: &BMSStops) -> f64 {
+        let stopped: f64 = stops.iter()
+            .take_while(|(pos, _)| pos < self)
+            .map(|(_, duration)| duration)
+            .sum();
+        self.to_absolute_time(timings, None) + stopped
+    }
+    pub fn from_absolute_time_with_stops(mut atime: f64, timings: &BMSTimings, stops: &BMSStops) -> BMSTime {
+        for (pos, duration) in stops {
+            let stop_beg = pos.to_absolute_time(timings, None);
+            if atime < stop_beg { break; }
+            if atime < stop_beg + duration { return *pos; }
+            atime -= duration;
+        }
+        BMSTime::from_absolute_time(atime, timings)
+    }
     pub fn bar(&self) -> usize {
         self.0.floor() as usize
     }
@@ -115,8 +135,8 @@ pub struct BMSAbsoluteTimingHint {
 #[test]
 fn test_bms_time_from_absolute_time_1() {
     let timings: BMSTimings = vec![
-        (0.0.into(), 120.0, 4),
-        (8.5.into(), 240.0, 4),
+        (0.0.into(), 120.0, 4.0),
+        (8.5.into(), 240.0, 4.0),
     ];
     let bmstime = BMSTime::from_absolute_time(10.0, &timings);
     assert_eq!(bmstime, 5.0.into());
@@ -126,8 +146,8 @@ fn test_bms_time_from_absolute_time_1() {
 #[test]
 fn test_bms_time_from_absolute_time_2() {
     let timings: BMSTimings = vec![
-        (0.0.into(), 120.0, 4),
-        (8.5.into(), 240.0, 4),
+        (0.0.into(), 120.0, 4.0),
+        (8.5.into(), 240.0, 4.0),
     ];
     let bmstime = BMSTime::from_absolute_time(20.0, &timings);
     assert_eq!(bmstime, 11.5.into());
@@ -137,9 +157,9 @@ fn test_bms_time_from_absolute_time_2() {
 #[test]
 fn test_bms_time_from_absolute_time_3() {
     let timings: BMSTimings = vec![
-        (0.0.into(), 120.0, 4),
-        (8.0.into(), 240.0, 4),
-        (9.0.into(), 120.0, 3),
+        (0.0.into(), 120.0, 4.0),
+        (8.0.into(), 240.0, 4.0),
+        (9.0.into(), 120.0, 3.0),
     ];
     let bmstime = BMSTime::from_absolute_time(20.0, &timings);
     assert_eq!(bmstime, 11.0.into());
@@ -149,8 +169,8 @@ fn test_bms_time_from_absolute_time_3() {
 #[test]
 fn test_bms_time_to_absolute_time_1() {
     let timings: BMSTimings = vec![
-        (0.0.into(), 120.0, 4),
-        (8.0.into(), 240.0, 4),
+        (0.0.into(), 120.0, 4.0),
+        (8.0.into(), 240.0, 4.0),
     ];
     let atime = BMSTime::from(4.5).to_absolute_time(&timings, None);
     assert_eq!(atime, 9.0);
@@ -160,8 +180,8 @@ fn test_bms_time_to_absolute_time_1() {
 #[test]
 fn test_bms_time_to_absolute_time_2() {
     let timings: BMSTimings = vec![
-        (0.0.into(), 120.0, 4),
-        (8.0.into(), 240.0, 4),
+        (0.0.into(), 120.0, 4.0),
+        (8.0.into(), 240.0, 4.0),
     ];
     let atime = BMSTime::from(8.0).to_absolute_time(&timings, None);
     assert_eq!(atime, 16.0);
@@ -171,9 +191,22 @@ fn test_bms_time_to_absolute_time_2() {
 #[test]
 fn test_bms_time_to_absolute_time_3() {
     let timings: BMSTimings = vec![
-        (0.0.into(), 120.0, 4),
-        (8.0.into(), 240.0, 4),
+        (0.0.into(), 120.0, 4.0),
+        (8.0.into(), 240.0, 4.0),
     ];
     let atime = BMSTime::from(9.5).to_absolute_time(&timings, None);
     assert_eq!(atime, 17.5);
+}
+
+#[cfg(test)]
+#[test]
+fn test_bms_time_with_stops() {
+    let timings: BMSTimings = vec![(0.0.into(), 120.0, 4.0)];
+    let stops: BMSStops = vec![(1.0.into(), 1.5), (2.0.into(), 0.5)];
+    assert_eq!(BMSTime::from(1.0).to_absolute_time_with_stops(&timings, &stops), 2.0);
+    assert_eq!(BMSTime::from(1.5).to_absolute_time_with_stops(&timings, &stops), 4.5);
+    assert_eq!(BMSTime::from(3.0).to_absolute_time_with_stops(&timings, &stops), 8.0);
+    assert_eq!(BMSTime::from_absolute_time_with_stops(3.0, &timings, &stops), 1.0.into());
+    assert_eq!(BMSTime::from_absolute_time_with_stops(4.5, &timings, &stops), 1.5.into());
+    assert_eq!(BMSTime::from_absolute_time_with_stops(8.0, &timings, &stops), 3.0.into());
 }
\ No newline at end of file
@@ -0,0 +1,150 @@
+use crate::cbms::BGM_CHANNEL;
+use crate::compiler::{ChartBuilder, ImportedBMS, beat_position};
+use crate::hash;
+
+use serde_json::Value;
+use std::fs::File;
+use std::io::Read;
+
+//Pulses per beat of charts that don't say
+pub const DEFAULT_RESOLUTION: f64 = 240.0;
+const DEFAULT_MODE_HINT: &str = "beat-7k";
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum BmsonImportError {
+    CouldntOpenFile,
+    ErrorReadingFile,
+    InvalidFormat,
+}
+
+#[derive(Debug)]
+pub struct BmsonChart {
+    //beat-7k, popn-9k...
+    pub mode_hint: String,
+    pub bms: ImportedBMS,
+}
+
+//Note channels of lanes 1 on. beat-* charts have the keys on 1-7 and the scratch on 8,
+//with the 2P side on 9-16, and popn-* charts are laid out like .pms files.
+pub fn lane_channels(mode_hint: &str) -> &'static [u32] {
+    if mode_hint.starts_with("popn") {
+        &[11, 12, 13, 14, 15, 22, 23, 24, 25]
+    } else {
+        &[11, 12, 13, 14, 15, 18, 19, 16, 21, 22, 23, 24, 25, 28, 29, 26]
+    }
+}
+
+fn number(object: &Value, key: &str) -> Option<f64> {
+    object.get(key)?.as_f64()
+}
+
+fn array<'v>(object: &'v Value, key: &str) -> &'v [Value] {
+    object.get(key).and_then(Value::as_array).map_or(&[], Vec::as_slice)
+}
+
+pub fn import_bmson_from_file(path: &str) -> Result<BmsonChart, BmsonImportError> {
+    let mut file = File::open(path)
+        .map_err(|_| BmsonImportError::CouldntOpenFile)?;
+    let mut raw_bmson = Vec::new();
+    file.read_to_end(&mut raw_bmson)
+        .map_err(|_| BmsonImportError::ErrorReadingFile)?;
+    import_bmson_bytes(&raw_bmson)
+}
+
+pub fn import_bmson_bytes(raw_bmson: &[u8]) -> Result<BmsonChart, BmsonImportError> {
+    let raw_bmson = std::str::from_utf8(raw_bmson).map_err(|_| BmsonImportError::InvalidFormat)?;
+    import_bmson(raw_bmson)
+}
+
+//Notes are placed by beat, the bar lines of the chart are left out. Notes on lane 0
+//and on lanes the mode doesn't have play as BGM.
+pub fn import_bmson(raw_bmson: &str) -> Result<BmsonChart, BmsonImportError> {
+    let bmson: Value = serde_json::from_str(raw_bmson).map_err(|_| BmsonImportError::InvalidFormat)?;
+    let info = bmson.get("info").filter(|info| info.is_object()).ok_or(BmsonImportError::InvalidFormat)?;
+    let mode_hint = info.get("mode_hint").and_then(Value::as_str).unwrap_or(DEFAULT_MODE_HINT).to_string();
+    let resolution = number(info, "resolution").filter(|r| *r > 0.0).unwrap_or(DEFAULT_RESOLUTION);
+    let init_bpm = number(info, "init_bpm").filter(|bpm| *bpm > 0.0).ok_or(BmsonImportError::InvalidFormat)?;
+    let position = |pulse: f64| beat_position(pulse / resolution);
+    let pulse = |object: &Value| number(object, "y").unwrap_or(0.0);
+    let mut builder = ChartBuilder::new(init_bpm as f32);
+    builder.set_title(info.get("title").and_then(Value::as_str).unwrap_or(""));
+    if let Some(level) = number(info, "level") {
+        builder.set_playlevel(level.max(0.0) as u32);
+    }
+    for event in array(&bmson, "bpm_events") {
+        if let Some(bpm) = number(event, "bpm").filter(|bpm| *bpm > 0.0) {
+            let (measure, num, den) = position(pulse(event));
+            builder.add_bpm_change(measure, num, den, bpm as f32);
+        }
+    }
+    //Durations are in pulses, 48 of a resolution to the 1/192 of a measure stops are in
+    for event in array(&bmson, "stop_events") {
+        let (measure, num, den) = position(pulse(event));
+        builder.add_stop(measure, num, den, number(event, "duration").unwrap_or(0.0) / resolution * 48.0);
+    }
+    let channels = lane_channels(&mode_hint);
+    //Each sound channel is a keysound, numbered from 1 in file order
+    for (idx, sound) in array(&bmson, "sound_channels").iter().enumerate() {
+        let keysound = idx as u32 + 1;
+        builder.set_keysound(keysound, sound.get("name").and_then(Value::as_str).unwrap_or(""));
+        for note in array(sound, "notes") {
+            let head = position(pulse(note));
+            let lane = number(note, "x").unwrap_or(0.0) as usize;
+            let length = number(note, "l").unwrap_or(0.0);
+            match lane.checked_sub(1).and_then(|lane| channels.get(lane)) {
+                None => builder.add_object(head.0, head.1, head.2, BGM_CHANNEL, keysound),
+                Some(&channel) if length > 0.0 => {
+                    let tail = position(pulse(note) + length);
+                    //11 to 51, the long note channel of the same lane
+                    builder.add_object(head.0, head.1, head.2, channel + 40, keysound);
+                    builder.add_object(tail.0, tail.1, tail.2, channel + 40, keysound);
+                },
+                Some(&channel) => builder.add_object(head.0, head.1, head.2, channel, keysound),
+            }
+        }
+    }
+    let mut bms = builder.build();
+    bms.md5 = hash::md5(raw_bmson.as_bytes());
+    bms.sha256 = hash::sha256(raw_bmson.as_bytes());
+    Ok(BmsonChart { mode_hint, bms })
+}
+
+#[cfg(test)]
+#[test]
+fn test_import_bmson() {
+    use crate::cbms::NoteKind;
+    let raw = r#"{
+        "version": "1.0.0",
+        "info": {"title": "Song", "level": 7, "init_bpm": 120, "resolution": 240},
+        "bpm_events": [{"y": 1920, "bpm": 240}],
+        "stop_events": [{"y": 2880, "duration": 480}],
+        "sound_channels": [
+            {"name": "bgm.ogg", "notes": [{"x": 0, "y": 0, "l": 0, "c": false}]},
+            {"name": "key.wav", "notes": [
+                {"x": 1, "y": 240, "l": 0, "c": false},
+                {"x": 8, "y": 960, "l": 480, "c": false},
+                {"x": 9, "y": 2880, "l": 0, "c": false},
+                {"x": 1, "y": 3360, "l": 0, "c": false}
+            ]}
+        ]
+    }"#;
+    let chart = import_bmson(raw).unwrap();
+    let bms = &chart.bms;
+    assert_eq!((chart.mode_hint.as_str(), bms.title.as_str(), bms.bpm, bms.playlevel), ("beat-7k", "Song", 120.0, Some(7)));
+    assert_eq!(&bms.resource_table[1 ..= 2], &["bgm.ogg", "key.wav"]);
+    assert_eq!(bms.md5, hash::md5(raw.as_bytes()));
+    let cbms = bms.eval_and_compile();
+    let notes: Vec<(u32, f64, NoteKind)> = cbms.playable_notes(&bms.timing, &bms.stops).iter()
+        .map(|n| (n.lane, n.time, n.kind))
+        .collect();
+    //240 BPM from beat 8, and a 2 beat stop at beat 12
+    assert_eq!(notes, vec![
+        (0, 0.5, NoteKind::Normal),
+        (5, 2.0, NoteKind::LongStart), (5, 3.0, NoteKind::LongEnd),
+        (9, 5.0, NoteKind::Normal),
+        (0, 6.0, NoteKind::Normal),
+    ]);
+    assert_eq!(import_bmson(r#"{"info": {"title": "No BPM"}}"#).err(), Some(BmsonImportError::InvalidFormat));
+    let popn = import_bmson(r#"{"info": {"init_bpm": 150, "mode_hint": "popn-9k"}, "sound_channels": [{"name": "", "notes": [{"x": 9, "y": 0}]}]}"#).unwrap();
+    assert_eq!(popn.bms.eval_and_compile().playable_notes(&popn.bms.timing, &popn.bms.stops)[0].lane, 13);
+}
@@ -1,9 +1,11 @@
 extern crate rand;
 
-mod player;
+pub mod player;
+pub mod stats;
+pub mod voice;
 
 use crate::util::pair_diff;
-use crate::bms::BMSTime;
+use crate::bms::{BMSTime, BMSTimings, BMSStops};
 
 use std::rc::*;
 
@@ -33,6 +35,108 @@ pub struct ChannelCommand {
     pub value: u32,
 }
 
+//Lanes are numbered per channel: 11-19 map to lanes 0-8 and 21-29 to lanes 9-17
+pub const LANES_PER_SIDE: u32 = 9;
+pub const BGM_CHANNEL: u32 = 1;
+pub const MEASURE_LENGTH_CHANNEL: u32 = 2;
+pub const BPM_CHANNEL: u32 = 3;
+pub const BGA_CHANNEL: u32 = 4;
+pub const BGA_POOR_CHANNEL: u32 = 6;
+pub const BGA_LAYER_CHANNEL: u32 = 7;
+pub const EXT_BPM_CHANNEL: u32 = 8;
+pub const STOP_CHANNEL: u32 = 9;
+//Mines are on channels D1-D9 and E1-E9, read as 131-139 and 141-149
+pub const MINE_CHANNEL: u32 = 131;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum BgaLayer {
+    Base,
+    Poor,
+    Layer,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ChannelKind {
+    Bgm,
+    Bpm,
+    ExtendedBpm,
+    Stop,
+    Bga(BgaLayer),
+    Note(u32),
+    Invisible(u32),
+    LongNote(u32),
+    Mine(u32),
+    Other,
+}
+
+impl ChannelCommand {
+    pub fn kind(&self) -> ChannelKind {
+        let lane = |base: u32| {
+            let (side, key) = ((self.channel - base) / 10, self.channel % 10);
+            if side < 2 && key != 0 { Some(side * LANES_PER_SIDE + key - 1) } else { None }
+        };
+        match self.channel {
+            BGM_CHANNEL => ChannelKind::Bgm,
+            BPM_CHANNEL => ChannelKind::Bpm,
+            EXT_BPM_CHANNEL => ChannelKind::ExtendedBpm,
+            STOP_CHANNEL => ChannelKind::Stop,
+            BGA_CHANNEL => ChannelKind::Bga(BgaLayer::Base),
+            BGA_POOR_CHANNEL => ChannelKind::Bga(BgaLayer::Poor),
+            BGA_LAYER_CHANNEL => ChannelKind::Bga(BgaLayer::Layer),
+            11 ..= 29 => lane(11).map_or(ChannelKind::Other, ChannelKind::Note),
+            31 ..= 49 => lane(31).map_or(ChannelKind::Other, ChannelKind::Invisible),
+            51 ..= 69 => lane(51).map_or(ChannelKind::Other, ChannelKind::LongNote),
+            131 ..= 149 => lane(MINE_CHANNEL).map_or(ChannelKind::Other, ChannelKind::Mine),
+            _ => ChannelKind::Other,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum NoteKind {
+    Normal,
+    LongStart,
+    LongEnd,
+}
+
+//A note the player has to hit
+#[derive(Copy, Clone, Debug)]
+pub struct PlayableNote {
+    pub bms_time: BMSTime,
+    pub time: f64,
+    pub lane: u32,
+    pub keysound: u32,
+    pub kind: NoteKind,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct TimedCommand {
+    pub bms_time: BMSTime,
+    pub time: f64,
+    pub command: ChannelCommand,
+}
+
+//Commands whose keysound is heard when the chart plays itself: BGM and every
+//visible note. Long notes only sound at their start, invisible notes never do.
+pub fn autoplay_commands(timed: &[TimedCommand]) -> Vec<TimedCommand> {
+    let mut ln_open = Vec::new();
+    timed.iter()
+        .filter(|tc| match tc.command.kind() {
+            ChannelKind::Bgm | ChannelKind::Note(_) => true,
+            ChannelKind::LongNote(lane) => {
+                if ln_open.len() <= lane as usize {
+                    ln_open.resize(lane as usize + 1, false);
+                }
+                ln_open[lane as usize] = !ln_open[lane as usize];
+                ln_open[lane as usize]
+            },
+            _ => false,
+        })
+        .cloned()
+        .collect()
+}
+
+
 #[derive(Debug)]
 pub struct CBMS {
     pub command_cnt: Rc<Vec<usize>>,
@@ -84,6 +188,55 @@ impl CBMS {
     pub fn command(&self, idx: usize) -> Option<ChannelCommand> {
         self.commands.get(idx).map(|v| *v)
     }
+    //All non-empty commands in chart order, with their absolute time in seconds
+    pub fn timed_commands(&self, timings: &BMSTimings, stops: &BMSStops) -> Vec<TimedCommand> {
+        self.iter()
+            .flatten()
+            .map(|(cmd_idx, bms_time)| (self.commands[cmd_idx], bms_time))
+            .filter(|(command, _)| command.value != 0)
+            .map(|(command, bms_time)| TimedCommand {
+                bms_time,
+                time: bms_time.to_absolute_time_with_stops(timings, stops),
+                command,
+            })
+            .collect()
+    }
+    //Every long note object counts, as both ends get judged
+    pub fn playable_note_count(&self) -> usize {
+        self.commands.iter()
+            .filter(|c| c.value != 0)
+            .filter(|c| matches!(c.kind(), ChannelKind::Note(_) | ChannelKind::LongNote(_)))
+            .count()
+    }
+    //Visible and long notes in chronological order. Long note starts without an end become normal notes.
+    pub fn playable_notes(&self, timings: &BMSTimings, stops: &BMSStops) -> Vec<PlayableNote> {
+        let mut notes = Vec::new();
+        let mut ln_open: Vec<Option<usize>> = vec![None; 2 * LANES_PER_SIDE as usize];
+        for tc in self.timed_commands(timings, stops) {
+            let (lane, kind) = match tc.command.kind() {
+                ChannelKind::Note(lane) => (lane, NoteKind::Normal),
+                ChannelKind::LongNote(lane) => match ln_open[lane as usize].take() {
+                    Some(_) => (lane, NoteKind::LongEnd),
+                    None => {
+                        ln_open[lane as usize] = Some(notes.len());
+                        (lane, NoteKind::LongStart)
+                    },
+                },
+                _ => continue,
+            };
+            notes.push(PlayableNote {
+                bms_time: tc.bms_time,
+                time: tc.time,
+                lane,
+                keysound: tc.command.value,
+                kind,
+            });
+        }
+        for idx in ln_open.into_iter().flatten() {
+            notes[idx].kind = NoteKind::Normal;
+        }
+        notes
+    }
 }
 
 //This fuckery is here only to make it possible to iterate over bms
@@ -139,11 +292,11 @@ impl Iterator for CBMSIterator {
     type Item = (std::ops::Range<usize>, BMSTime);
     fn next(&mut self) -> Option<(std::ops::Range<usize>, BMSTime)> {
         //Return None if no more commands are avaible to pull
-        if self.current_set > self.measure_sets.len() - 1 { return None; }
+        if self.current_set >= self.measure_sets.len() { return None; }
         //Jump to next command set if all commands from the current set were already pulled and if no more commands are avaible to pull return None 
         while self.current_cmd_cnt_pos >= self.measure_sets[self.current_set].command_cnt_idx.1 {
             self.current_set += 1;
-            if self.current_set >= self.measure_sets.len() - 1 { return None; }
+            if self.current_set >= self.measure_sets.len() { return None; }
         }
         let measure_set = self.measure_sets[self.current_set];
         //Obtain command count and commands
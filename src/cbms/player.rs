@@ -1,13 +1,477 @@
 use super::*;
+use super::voice::*;
+
+use crate::judge::KeyEvent;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum PlayerEvent {
+    //A playable note reaches the judge line
+    Note { lane: u32, keysound: u32 },
+    InvisibleNote { lane: u32, keysound: u32 },
+    LongNoteStart { lane: u32, keysound: u32 },
+    LongNoteEnd { lane: u32, keysound: u32 },
+    //Damage is the raw object value
+    Mine { lane: u32, damage: u32 },
+    Bgm { keysound: u32 },
+    BpmChange(f32),
+    //Duration of the stop in seconds
+    StopStart(f64),
+    StopEnd,
+    BgaChange { layer: BgaLayer, id: u32 },
+    MeasureLine(u32),
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct TimedEvent {
+    pub time: f64,
+    pub bms_time: BMSTime,
+    pub event: PlayerEvent,
+}
+
+//Events `advance` reached, in the order they were played: a range of events for every loop pass
+pub struct PlayerEvents<'a> {
+    events: &'a [TimedEvent],
+    passes: std::vec::IntoIter<std::ops::Range<usize>>,
+    current: std::slice::Iter<'a, TimedEvent>,
+}
+
+impl<'a> Iterator for PlayerEvents<'a> {
+    type Item = &'a TimedEvent;
+    fn next(&mut self) -> Option<&'a TimedEvent> {
+        loop {
+            if let Some(event) = self.current.next() { return Some(event); }
+            let pass = self.passes.next()?;
+            self.current = self.events[pass].iter();
+        }
+    }
+}
+
+//Snapshot of everything that lasts longer than a single event
+#[derive(Clone, PartialEq, Debug)]
+pub struct PlayerState {
+    pub bpm: f32,
+    pub bga: Option<u32>,
+    pub bga_poor: Option<u32>,
+    pub bga_layer: Option<u32>,
+    //Time left until the ongoing stop ends
+    pub stop_remaining: Option<f64>,
+    pub held_long_notes: Vec<u32>,
+}
+
+//A-B loop over [start, end) in seconds, the playback rate grows by `speed_up` after every pass
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct LoopRange {
+    pub start: f64,
+    pub end: f64,
+    pub speed_up: f64,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct LongNoteSpan {
+    lane: u32,
+    start: f64,
+    end: f64,
+}
 
 pub struct CBMSPlayer<'bms> {
     cbms: &'bms CBMS,
+    timings: &'bms BMSTimings,
+    stops: &'bms BMSStops,
+    voices: VoiceAllocator,
+    events: Vec<TimedEvent>,
+    //State changes split by kind, so the state at any time is a binary search away
+    bpm_changes: Vec<(f64, f32)>,
+    bga_changes: [Vec<(f64, u32)>; 3],
+    stop_spans: Vec<(f64, f64)>,
+    long_notes: Vec<LongNoteSpan>,
+    position: f64,
+    next_event: usize,
+    rate: f64,
+    loop_range: Option<LoopRange>,
+    loop_count: usize,
 }
 
 impl<'b> CBMSPlayer<'b> {
-    pub fn new(cbms: &'b CBMS) -> Self {
-        Self {
+    pub fn new(cbms: &'b CBMS, timings: &'b BMSTimings, stops: &'b BMSStops) -> Self {
+        Self::with_max_polyphony(cbms, timings, stops, DEFAULT_MAX_POLYPHONY)
+    }
+    pub fn with_max_polyphony(cbms: &'b CBMS, timings: &'b BMSTimings, stops: &'b BMSStops, max_polyphony: usize) -> Self {
+        let mut player = Self {
             cbms,
+            timings,
+            stops,
+            voices: VoiceAllocator::new(max_polyphony),
+            events: Vec::new(),
+            bpm_changes: Vec::new(),
+            bga_changes: [Vec::new(), Vec::new(), Vec::new()],
+            stop_spans: Vec::new(),
+            long_notes: Vec::new(),
+            position: 0.0,
+            next_event: 0,
+            rate: 1.0,
+            loop_range: None,
+            loop_count: 0,
+        };
+        player.schedule_voices();
+        player.schedule_events();
+        player
+    }
+    //Lengths in seconds indexed by keysound id, without them voices only end when cut
+    pub fn set_keysound_lengths(&mut self, lengths: Vec<Option<f64>>) {
+        self.voices.set_keysound_lengths(lengths);
+        self.schedule_voices();
+    }
+    fn schedule_voices(&mut self) {
+        self.voices.clear();
+        for tc in autoplay_commands(&self.cbms.timed_commands(self.timings, self.stops)) {
+            self.voices.trigger(tc.command.value, tc.time);
+        }
+    }
+    fn schedule_events(&mut self) {
+        let mut events = Vec::new();
+        let mut push = |bms_time: BMSTime, time: f64, event: PlayerEvent| events.push(TimedEvent { time, bms_time, event });
+        let abs_time = |bms_time: BMSTime| bms_time.to_absolute_time_with_stops(self.timings, self.stops);
+        for measure in 0 .. self.cbms.bar_count() as u32 {
+            let bms_time = BMSTime::from(measure as f64);
+            push(bms_time, abs_time(bms_time), PlayerEvent::MeasureLine(measure));
+        }
+        //Segments that only change the measure length keep the BPM
+        for pair in self.timings.windows(2) {
+            let ((_, prev_bpm, _), (bms_time, bpm, _)) = (pair[0], pair[1]);
+            if bpm != prev_bpm {
+                push(bms_time, abs_time(bms_time), PlayerEvent::BpmChange(bpm));
+            }
+        }
+        for &(bms_time, duration) in self.stops {
+            let time = abs_time(bms_time);
+            push(bms_time, time, PlayerEvent::StopStart(duration));
+            push(bms_time, time + duration, PlayerEvent::StopEnd);
         }
+        let mut ln_open = vec![false; 2 * LANES_PER_SIDE as usize];
+        for tc in self.cbms.timed_commands(self.timings, self.stops) {
+            let keysound = tc.command.value;
+            let event = match tc.command.kind() {
+                ChannelKind::Bgm => PlayerEvent::Bgm { keysound },
+                ChannelKind::Note(lane) => PlayerEvent::Note { lane, keysound },
+                ChannelKind::Invisible(lane) => PlayerEvent::InvisibleNote { lane, keysound },
+                ChannelKind::LongNote(lane) => {
+                    ln_open[lane as usize] = !ln_open[lane as usize];
+                    if ln_open[lane as usize] {
+                        PlayerEvent::LongNoteStart { lane, keysound }
+                    } else {
+                        PlayerEvent::LongNoteEnd { lane, keysound }
+                    }
+                },
+                ChannelKind::Mine(lane) => PlayerEvent::Mine { lane, damage: tc.command.value },
+                ChannelKind::Bga(layer) => PlayerEvent::BgaChange { layer, id: tc.command.value },
+                _ => continue,
+            };
+            push(tc.bms_time, tc.time, event);
+        }
+        //Stable, so events at the same time keep the order above
+        events.sort_by(|a, b| a.time.total_cmp(&b.time));
+        self.events = events;
+        self.index_state_changes();
+    }
+    fn index_state_changes(&mut self) {
+        let initial_bpm = self.timings.first().map_or(0.0, |t| t.1);
+        self.bpm_changes = vec![(f64::NEG_INFINITY, initial_bpm)];
+        let mut ln_starts = vec![None; 2 * LANES_PER_SIDE as usize];
+        for e in &self.events {
+            match e.event {
+                PlayerEvent::BpmChange(bpm) => self.bpm_changes.push((e.time, bpm)),
+                PlayerEvent::BgaChange { layer, id } => self.bga_changes[bga_layer_idx(layer)].push((e.time, id)),
+                PlayerEvent::StopStart(duration) => self.stop_spans.push((e.time, e.time + duration)),
+                PlayerEvent::LongNoteStart { lane, .. } => ln_starts[lane as usize] = Some(e.time),
+                PlayerEvent::LongNoteEnd { lane, .. } => if let Some(start) = ln_starts[lane as usize].take() {
+                    self.long_notes.push(LongNoteSpan { lane, start, end: e.time });
+                },
+                _ => (),
+            }
+        }
+        //Pushed as they end, `state_at` needs them in the order they start
+        self.long_notes.sort_by(|a, b| a.start.total_cmp(&b.start));
+    }
+    pub fn max_polyphony(&self) -> usize {
+        self.voices.max_polyphony()
+    }
+    pub fn voices(&self) -> &[Voice] {
+        self.voices.voices()
     }
-}
\ No newline at end of file
+    pub fn active_voices(&self, time: f64) -> Vec<Voice> {
+        self.voices.active_voices(time)
+    }
+    pub fn events(&self) -> &[TimedEvent] {
+        &self.events
+    }
+    //Current position in seconds
+    pub fn position(&self) -> f64 {
+        self.position
+    }
+    pub fn bms_position(&self) -> BMSTime {
+        BMSTime::from_absolute_time_with_stops(self.position, self.timings, self.stops)
+    }
+    pub fn is_finished(&self) -> bool {
+        self.next_event >= self.events.len()
+    }
+    //Moves the clock forward by `dt` seconds (scaled by the playback rate) and returns
+    //every event reached on the way. Passing the end of the loop wraps back to its start.
+    pub fn advance(&mut self, dt: f64) -> PlayerEvents<'_> {
+        let mut target = self.position + dt.max(0.0) * self.rate;
+        let mut beg = self.next_event;
+        let mut passes = Vec::new();
+        //Every time the end of the loop is passed is a pass of its own, however long `dt` is
+        if let Some(range) = self.loop_range.filter(|range| self.position < range.end) {
+            while target >= range.end {
+                passes.push(beg .. self.events.partition_point(|e| e.time < range.end).max(beg));
+                target = range.start + (target - range.end);
+                self.loop_count += 1;
+                self.rate += range.speed_up;
+                beg = self.events.partition_point(|e| e.time < range.start);
+            }
+        }
+        let end = self.events.partition_point(|e| e.time <= target).max(beg);
+        passes.push(beg .. end);
+        self.position = target;
+        self.next_event = end;
+        PlayerEvents {
+            events: &self.events,
+            passes: passes.into_iter(),
+            current: [].iter(),
+        }
+    }
+    //Callback flavour of `advance`
+    pub fn advance_with<F>(&mut self, dt: f64, mut callback: F) where F: FnMut(&TimedEvent) {
+        for event in self.advance(dt) {
+            callback(event);
+        }
+    }
+    //Jumps to `seconds` without emitting anything, events at exactly that time come with the next advance
+    pub fn seek_to(&mut self, seconds: f64) {
+        self.position = seconds.max(0.0);
+        let position = self.position;
+        self.next_event = self.events.partition_point(|e| e.time < position);
+    }
+    pub fn seek_to_measure(&mut self, measure: u32) {
+        let seconds = BMSTime::from(measure as f64).to_absolute_time_with_stops(self.timings, self.stops);
+        self.seek_to(seconds);
+    }
+    pub fn state(&self) -> PlayerState {
+        self.state_at(self.position)
+    }
+    pub fn state_at(&self, time: f64) -> PlayerState {
+        let last_before = |changes: &[(f64, u32)]| {
+            let idx = changes.partition_point(|c| c.0 <= time);
+            if idx > 0 { Some(changes[idx - 1].1) } else { None }
+        };
+        let bpm_idx = self.bpm_changes.partition_point(|c| c.0 <= time);
+        let stop_idx = self.stop_spans.partition_point(|s| s.0 <= time);
+        let stop_remaining = if stop_idx > 0 && self.stop_spans[stop_idx - 1].1 > time {
+            Some(self.stop_spans[stop_idx - 1].1 - time)
+        } else { None };
+        PlayerState {
+            bpm: self.bpm_changes[bpm_idx.max(1) - 1].1,
+            bga: last_before(&self.bga_changes[bga_layer_idx(BgaLayer::Base)]),
+            bga_poor: last_before(&self.bga_changes[bga_layer_idx(BgaLayer::Poor)]),
+            bga_layer: last_before(&self.bga_changes[bga_layer_idx(BgaLayer::Layer)]),
+            stop_remaining,
+            held_long_notes: self.long_notes.iter()
+                .take_while(|ln| ln.start <= time)
+                .filter(|ln| time < ln.end)
+                .map(|ln| ln.lane)
+                .collect(),
+        }
+    }
+    pub fn playback_rate(&self) -> f64 {
+        self.rate
+    }
+    pub fn set_playback_rate(&mut self, rate: f64) {
+        self.rate = rate.max(0.0);
+    }
+    pub fn set_loop(&mut self, range: Option<LoopRange>) {
+        self.loop_range = range.filter(|r| r.end > r.start);
+        self.loop_count = 0;
+    }
+    pub fn loop_range(&self) -> Option<LoopRange> {
+        self.loop_range
+    }
+    pub fn loop_count(&self) -> usize {
+        self.loop_count
+    }
+    pub fn autoplay_inputs(&self) -> Vec<KeyEvent> {
+        autoplay_inputs(&self.cbms.playable_notes(self.timings, self.stops))
+    }
+}
+
+//How long autoplay keeps a key down after hitting a normal note
+pub const AUTOPLAY_TAP_LENGTH: f64 = 0.05;
+
+//Perfectly timed key presses for every judged note. Long notes are held until
+//their end, other notes are released after a short tap or halfway to the next
+//note on the same lane, whichever comes first. Invisible notes are never pressed.
+pub fn autoplay_inputs(notes: &[PlayableNote]) -> Vec<KeyEvent> {
+    let mut lanes: Vec<Vec<&PlayableNote>> = Vec::new();
+    for note in notes {
+        if lanes.len() <= note.lane as usize {
+            lanes.resize(note.lane as usize + 1, Vec::new());
+        }
+        lanes[note.lane as usize].push(note);
+    }
+    let mut inputs = Vec::new();
+    for lane_notes in &lanes {
+        for (idx, note) in lane_notes.iter().enumerate() {
+            let next = lane_notes.get(idx + 1).map(|n| n.time);
+            match note.kind {
+                NoteKind::Normal => {
+                    let mut release = note.time + AUTOPLAY_TAP_LENGTH;
+                    if let Some(next) = next {
+                        release = release.min((note.time + next) / 2.0);
+                    }
+                    inputs.push(KeyEvent { time: note.time, lane: note.lane, pressed: true });
+                    inputs.push(KeyEvent { time: release, lane: note.lane, pressed: false });
+                },
+                NoteKind::LongStart => inputs.push(KeyEvent { time: note.time, lane: note.lane, pressed: true }),
+                NoteKind::LongEnd => inputs.push(KeyEvent { time: note.time, lane: note.lane, pressed: false }),
+            }
+        }
+    }
+    //Releases go first so a key let go and pressed again at the same time stays pressed
+    inputs.sort_by(|a, b| a.time.total_cmp(&b.time).then(a.pressed.cmp(&b.pressed)));
+    inputs
+}
+
+fn bga_layer_idx(layer: BgaLayer) -> usize {
+    match layer {
+        BgaLayer::Base => 0,
+        BgaLayer::Poor => 1,
+        BgaLayer::Layer => 2,
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_player_active_voices() {
+    //Keysound 01 is retriggered every half a second and cuts itself
+    let bms = crate::compiler::import_bms("#BPM 240\n#00011:0101\n#00001:02").unwrap();
+    let cbms = bms.eval_and_compile();
+    let mut player = CBMSPlayer::new(&cbms, &bms.timing, &bms.stops);
+    player.set_keysound_lengths(vec![None, Some(1.0), Some(1.0)]);
+    let mut active: Vec<u32> = player.active_voices(0.75).iter().map(|v| v.keysound).collect();
+    active.sort();
+    assert_eq!(active, vec![1, 2]);
+    assert_eq!(player.voices().iter().filter(|v| v.keysound == 1).count(), 2);
+    assert_eq!(player.active_voices(1.25).len(), 1);
+}
+
+#[cfg(test)]
+#[test]
+fn test_player_advance_emits_events() {
+    //One second measures, then a stop of half a measure and a BPM change to 120
+    let bms = crate::compiler::import_bms("#BPM 240\n#STOP01 96\n#00011:01\n#00109:01\n#00103:0078\n#00216:02").unwrap();
+    let cbms = bms.eval_and_compile();
+    let mut player = CBMSPlayer::new(&cbms, &bms.timing, &bms.stops);
+    let first: Vec<PlayerEvent> = player.advance(0.5).map(|e| e.event).collect();
+    assert_eq!(first, vec![PlayerEvent::MeasureLine(0), PlayerEvent::Note { lane: 0, keysound: 1 }]);
+    let second: Vec<(f64, PlayerEvent)> = player.advance(1.0).map(|e| (e.time, e.event)).collect();
+    assert_eq!(second, vec![
+        (1.0, PlayerEvent::MeasureLine(1)),
+        (1.0, PlayerEvent::StopStart(0.5)),
+        (1.5, PlayerEvent::StopEnd),
+    ]);
+    let mut rest = Vec::new();
+    player.advance_with(2.0, |e| rest.push((e.time, e.event)));
+    assert_eq!(rest, vec![
+        (2.0, PlayerEvent::BpmChange(120.0)),
+        (3.0, PlayerEvent::MeasureLine(2)),
+        (3.0, PlayerEvent::Note { lane: 5, keysound: 2 }),
+    ]);
+    assert!(player.is_finished());
+    player.seek_to(1.5);
+    assert_eq!(player.advance(0.0).count(), 1);
+    assert_eq!(player.bms_position(), 1.0.into());
+}
+
+#[cfg(test)]
+#[test]
+fn test_player_real_bpm() {
+    //A measure of 0.3 plays at the chart's BPM, without a BPM change
+    let bms = crate::compiler::import_bms("#BPM 120\n#00102:0.3\n#00211:01").unwrap();
+    let cbms = bms.eval_and_compile();
+    let player = CBMSPlayer::new(&cbms, &bms.timing, &bms.stops);
+    assert!(player.events().iter().all(|e| !matches!(e.event, PlayerEvent::BpmChange(_))));
+    assert_eq!(player.state_at(2.1).bpm, 120.0);
+    //Charts without #BPM play at 130
+    let bms = crate::compiler::import_bms("#00011:01\n#00111:01").unwrap();
+    let cbms = bms.eval_and_compile();
+    let player = CBMSPlayer::new(&cbms, &bms.timing, &bms.stops);
+    assert_eq!(player.events().len(), 4);
+    assert_eq!(player.state().bpm, crate::compiler::DEFAULT_BPM);
+}
+
+#[cfg(test)]
+#[test]
+fn test_player_state_after_seek() {
+    //BPM 120 from measure 1, a BGA from measure 1, a long note over measure 2 and a stop in measure 3
+    let bms = crate::compiler::import_bms("#BPM 240\n#STOP01 192\n#00103:78\n#00104:05\n#00251:0101\n#00309:01").unwrap();
+    let cbms = bms.eval_and_compile();
+    let mut player = CBMSPlayer::new(&cbms, &bms.timing, &bms.stops);
+    assert_eq!(player.state().bpm, 240.0);
+    player.seek_to_measure(2);
+    assert_eq!(player.position(), 3.0);
+    player.seek_to(3.5);
+    let state = player.state();
+    assert_eq!((state.bpm, state.bga, state.held_long_notes), (120.0, Some(5), vec![0]));
+    player.seek_to(5.5);
+    let state = player.state();
+    assert_eq!((state.stop_remaining, state.held_long_notes.len()), (Some(1.5), 0));
+}
+
+#[cfg(test)]
+#[test]
+fn test_player_loop() {
+    let bms = crate::compiler::import_bms("#BPM 240\n#00011:01010101\n#00111:01").unwrap();
+    let cbms = bms.eval_and_compile();
+    let mut player = CBMSPlayer::new(&cbms, &bms.timing, &bms.stops);
+    player.set_loop(Some(LoopRange { start: 0.25, end: 0.75, speed_up: 0.5 }));
+    let times: Vec<f64> = player.advance(1.0).map(|e| e.time).collect();
+    assert_eq!(times, vec![0.0, 0.0, 0.25, 0.5, 0.25, 0.5]);
+    assert_eq!((player.position(), player.loop_count(), player.playback_rate()), (0.5, 1, 1.5));
+    let times: Vec<f64> = player.advance(0.25).map(|e| e.time).collect();
+    assert_eq!(times, vec![0.25]);
+    //Two and a half passes in one go
+    player.set_loop(Some(LoopRange { start: 0.25, end: 0.75, speed_up: 0.0 }));
+    player.seek_to(0.0);
+    player.set_playback_rate(1.0);
+    let times: Vec<f64> = player.advance(2.0).map(|e| e.time).collect();
+    assert_eq!(times, vec![0.0, 0.0, 0.25, 0.5, 0.25, 0.5, 0.25, 0.5, 0.25, 0.5]);
+    assert_eq!((player.position(), player.loop_count()), (0.5, 3));
+}
+
+#[cfg(test)]
+#[test]
+fn test_player_held_long_notes_overlapping() {
+    //A long note over measures 0 to 4 on lane 0 and a shorter one in measure 1 on lane 1
+    let bms = crate::compiler::import_bms("#BPM 240\n#00051:01\n#00451:01\n#00152:0101").unwrap();
+    let cbms = bms.eval_and_compile();
+    let player = CBMSPlayer::new(&cbms, &bms.timing, &bms.stops);
+    assert_eq!(player.state_at(0.5).held_long_notes, vec![0]);
+    assert_eq!(player.state_at(1.25).held_long_notes, vec![0, 1]);
+    assert_eq!(player.state_at(3.0).held_long_notes, vec![0]);
+}
+
+#[cfg(test)]
+#[test]
+fn test_autoplay_inputs_play_perfectly() {
+    //A jack on lane 0, a long note on lane 1 and an invisible note on lane 2
+    let bms = crate::compiler::import_bms("#BPM 240\n#00011:01010101\n#00052:0101\n#00033:01\n#00111:01").unwrap();
+    let cbms = bms.eval_and_compile();
+    let player = CBMSPlayer::new(&cbms, &bms.timing, &bms.stops);
+    let inputs = player.autoplay_inputs();
+    assert_eq!(inputs.len(), 12);
+    assert!(inputs.iter().all(|e| e.lane != 2));
+    assert_eq!(inputs.iter().filter(|e| e.lane == 1).map(|e| (e.time, e.pressed)).collect::<Vec<_>>(), vec![(0.0, true), (0.5, false)]);
+    let notes = cbms.playable_notes(&bms.timing, &bms.stops);
+    let results = crate::judge::judge_inputs(notes, crate::judge::JudgeWindows::from_rank(0), &inputs);
+    assert_eq!(results.len(), 7);
+    assert!(results.iter().all(|r| r.judgement == crate::judge::Judgement::PGreat));
+}
@@ -0,0 +1,110 @@
+use super::*;
+
+//Width of the sliding window notes per second are counted over
+pub const DENSITY_WINDOW: f64 = 1.0;
+//Distance between the samples of the density graph
+pub const DENSITY_STEP: f64 = 0.5;
+
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct ChartStats {
+    //Judged notes, both ends of long notes included
+    pub note_count: usize,
+    pub lane_counts: Vec<usize>,
+    pub scratch_count: usize,
+    pub long_note_count: usize,
+    pub invisible_count: usize,
+    pub mine_count: usize,
+    pub bgm_count: usize,
+    //Notes per second in the window starting at each sample, see `DENSITY_STEP`
+    pub density: Vec<(f64, f64)>,
+    pub peak_nps: f64,
+    pub average_nps: f64,
+    //Time of the last object in seconds
+    pub length: f64,
+}
+
+//Densest `window` long stretch of `times`, which have to be sorted. Returns notes per second.
+pub fn peak_density(times: &[f64], window: f64) -> f64 {
+    let mut end = 0;
+    let mut best = 0;
+    for (beg, &start) in times.iter().enumerate() {
+        while end < times.len() && times[end] < start + window {
+            end += 1;
+        }
+        best = best.max(end - beg);
+    }
+    best as f64 / window
+}
+
+//Notes per second in [t, t + window) for t stepping by `step` over `length`
+pub fn density_graph(times: &[f64], length: f64, window: f64, step: f64) -> Vec<(f64, f64)> {
+    let (mut beg, mut end) = (0, 0);
+    let mut graph = Vec::new();
+    let mut t = 0.0;
+    while t <= length {
+        while beg < times.len() && times[beg] < t {
+            beg += 1;
+        }
+        while end < times.len() && times[end] < t + window {
+            end += 1;
+        }
+        graph.push((t, end.saturating_sub(beg) as f64 / window));
+        t += step;
+    }
+    graph
+}
+
+impl CBMS {
+    pub fn statistics(&self, timings: &BMSTimings, stops: &BMSStops) -> ChartStats {
+        self.statistics_with_window(timings, stops, DENSITY_WINDOW, DENSITY_STEP)
+    }
+    pub fn statistics_with_window(&self, timings: &BMSTimings, stops: &BMSStops, window: f64, step: f64) -> ChartStats {
+        let timed = self.timed_commands(timings, stops);
+        let notes = self.playable_notes(timings, stops);
+        let mut stats = ChartStats {
+            note_count: notes.len(),
+            lane_counts: vec![0; 2 * LANES_PER_SIDE as usize],
+            length: timed.iter().map(|tc| tc.time).fold(0.0, f64::max),
+            ..Default::default()
+        };
+        for note in &notes {
+            stats.lane_counts[note.lane as usize] += 1;
+            if note.kind == NoteKind::LongStart {
+                stats.long_note_count += 1;
+            }
+        }
+        //The turntable is key 6 of each side
+        stats.scratch_count = stats.lane_counts[5] + stats.lane_counts[LANES_PER_SIDE as usize + 5];
+        for tc in &timed {
+            match tc.command.kind() {
+                ChannelKind::Bgm => stats.bgm_count += 1,
+                ChannelKind::Invisible(_) => stats.invisible_count += 1,
+                ChannelKind::Mine(_) => stats.mine_count += 1,
+                _ => (),
+            }
+        }
+        let times: Vec<f64> = notes.iter().map(|n| n.time).collect();
+        stats.peak_nps = peak_density(&times, window);
+        stats.density = density_graph(&times, stats.length, window, step);
+        if stats.length > 0.0 {
+            stats.average_nps = stats.note_count as f64 / stats.length;
+        }
+        stats
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_chart_statistics() {
+    //Two one second measures: 4 notes, a long note on the scratch, a mine, an invisible note and 2 BGM objects
+    let bms = crate::compiler::import_bms("#BPM 240\n#00011:01010101\n#00156:0101\n#000D2:01\n#00031:01\n#00101:0101").unwrap();
+    let stats = bms.eval_and_compile().statistics(&bms.timing, &bms.stops);
+    assert_eq!(stats.note_count, 6);
+    assert_eq!((stats.lane_counts[0], stats.lane_counts[5]), (4, 2));
+    assert_eq!((stats.scratch_count, stats.long_note_count), (2, 1));
+    assert_eq!((stats.mine_count, stats.invisible_count, stats.bgm_count), (1, 1, 2));
+    assert_eq!(stats.length, 1.5);
+    assert_eq!(stats.peak_nps, 4.0);
+    assert_eq!(stats.average_nps, 4.0);
+    assert_eq!(stats.density, vec![(0.0, 4.0), (0.5, 3.0), (1.0, 2.0), (1.5, 1.0)]);
+}
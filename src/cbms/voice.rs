@@ -0,0 +1,107 @@
+//Keysound voice allocation. Retriggering a keysound cuts its previous instance
+//and once all voices are taken the oldest one is stolen.
+
+pub const DEFAULT_MAX_POLYPHONY: usize = 64;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Voice {
+    pub keysound: u32,
+    pub start: f64,
+    pub end: f64,
+}
+
+impl Voice {
+    pub fn is_active_at(&self, time: f64) -> bool {
+        self.start <= time && time < self.end
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct VoiceAllocator {
+    max_polyphony: usize,
+    //Keysound lengths in seconds, None (or a missing entry) means the voice plays until it's cut
+    keysound_lengths: Vec<Option<f64>>,
+    voices: Vec<Voice>,
+    //Indices of voices which weren't cut or finished as of the last trigger
+    playing: Vec<usize>,
+}
+
+impl VoiceAllocator {
+    pub fn new(max_polyphony: usize) -> Self {
+        Self {
+            max_polyphony: max_polyphony.max(1),
+            keysound_lengths: Vec::new(),
+            voices: Vec::new(),
+            playing: Vec::new(),
+        }
+    }
+    pub fn max_polyphony(&self) -> usize {
+        self.max_polyphony
+    }
+    pub fn set_keysound_lengths(&mut self, lengths: Vec<Option<f64>>) {
+        self.keysound_lengths = lengths;
+    }
+    //Triggers must come in chronological order. Returns the index of the new voice.
+    pub fn trigger(&mut self, keysound: u32, time: f64) -> usize {
+        let voices = &mut self.voices;
+        self.playing.retain(|&idx| voices[idx].end > time);
+        //Retrigger cuts the previous instance of the same keysound
+        if let Some(pos) = self.playing.iter().position(|&idx| voices[idx].keysound == keysound) {
+            voices[self.playing.remove(pos)].end = time;
+        }
+        //Steal the oldest voice when out of polyphony
+        if self.playing.len() >= self.max_polyphony {
+            voices[self.playing.remove(0)].end = time;
+        }
+        let length = self.keysound_lengths.get(keysound as usize).cloned().flatten();
+        voices.push(Voice {
+            keysound,
+            start: time,
+            end: length.map_or(f64::INFINITY, |l| time + l),
+        });
+        self.playing.push(voices.len() - 1);
+        voices.len() - 1
+    }
+    pub fn clear(&mut self) {
+        self.voices.clear();
+        self.playing.clear();
+    }
+    pub fn voices(&self) -> &[Voice] {
+        &self.voices
+    }
+    pub fn active_voices(&self, time: f64) -> Vec<Voice> {
+        self.voices.iter().filter(|v| v.is_active_at(time)).cloned().collect()
+    }
+}
+
+impl Default for VoiceAllocator {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_POLYPHONY)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_voice_retrigger_cuts_previous() {
+    let mut alloc = VoiceAllocator::default();
+    alloc.set_keysound_lengths(vec![None, Some(2.0), Some(2.0)]);
+    alloc.trigger(1, 0.0);
+    alloc.trigger(2, 0.5);
+    alloc.trigger(1, 1.0);
+    assert_eq!(alloc.voices()[0].end, 1.0);
+    assert_eq!(alloc.voices()[1].end, 2.5);
+    let active: Vec<u32> = alloc.active_voices(1.5).iter().map(|v| v.keysound).collect();
+    assert_eq!(active, vec![2, 1]);
+}
+
+#[cfg(test)]
+#[test]
+fn test_voice_polyphony_steals_oldest() {
+    let mut alloc = VoiceAllocator::new(2);
+    alloc.trigger(1, 0.0);
+    alloc.trigger(2, 1.0);
+    alloc.trigger(3, 2.0);
+    assert_eq!(alloc.voices()[0].end, 2.0);
+    assert_eq!(alloc.active_voices(2.0).len(), 2);
+    assert_eq!(alloc.active_voices(1.5).len(), 2);
+}
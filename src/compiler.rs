@@ -6,20 +6,21 @@ use regex::Regex;
 use num::integer::lcm;
 use std::str::FromStr;
 use std::fs::File;
-use std::io::Read;
+use std::io::{BufRead, BufReader};
 use std::collections::HashMap;
 use std::rc::Rc;
+use std::borrow::Cow;
 
 lazy_static!{
-    static ref CHANNEL_CMD_REGEX: Regex = Regex::new(r"#(?P<measure>[0-9]{3})(?P<channel>[0-9]{2}):(?P<indices>[[:alnum:]]*)").unwrap();
-    static ref HEADER_TITLE_REGEX: Regex = Regex::new(r"#TITLE (?P<title>[[:alnum:]]*)").unwrap();
-    static ref BPM_REGEX: Regex = Regex::new(r"#BPM (?P<bpm>[[:alnum:]]*)").unwrap();
-    static ref WAV_REGEX: Regex = Regex::new(r"WAV(?P<idx>[[:alnum:]]{2}) (?P<path>.*)").unwrap();
+    static ref CHANNEL_CMD_REGEX: Regex = Regex::new(r"^#(?P<measure>[0-9]{3})(?P<channel>[[:alnum:]]{2}):(?P<data>.*)").unwrap();
+    static ref HEADER_REGEX: Regex = Regex::new(r"^#(?P<name>[[:alnum:]]+)(?:\s+(?P<value>.*))?$").unwrap();
 }
 
 use crate::cbms::*;
 use crate::util::pair_diff;
-use crate::bms::{BMSTimings, BMSTime};
+use crate::bms::{BMSTimings, BMSStops, BMSTime};
+use crate::hash;
+use crate::dialect::{Dialect, Header, bms_channel_name, parse_id};
 
 #[derive(Copy, Clone, Debug)]
 pub enum BMSImportError {
@@ -30,23 +31,37 @@ pub enum BMSImportError {
 }
 
 #[derive(Clone, Debug)]
-enum BMSCommand {
+pub enum BMSCommand {
     Channel(ChannelCommandSet),
     WAVResource {idx: u32, path: String },
+    ExtendedBPM { idx: u32, bpm: f32 },
+    //Stop length in 1/192 of a 4/4 measure
+    StopLength { idx: u32, length: f64 },
+    MeasureLength { measure: u32, length: f64 },
     SongInfo(BMSSongInfo),
-    //Other,
+    //Base object ids are written in from here on
+    Base(u32),
+    //A line we don't understand, kept as it was so writing the chart back doesn't lose it
+    Other(String),
 }
 
 #[derive(Clone, Debug)]
-enum BMSSongInfo {
+pub enum BMSSongInfo {
     Title(String),
     BPM(f32),
+    VolWav(u32),
+    Preview(String),
+    Rank(u32),
+    DefExRank(f64),
+    Total(f64),
+    PlayLevel(u32),
 }
 
 #[derive(Copy, Clone, Debug)]
-struct ChannelCommandSet {
-    measure: u32,
-    channel: u32,
+pub struct ChannelCommandSet {
+    pub measure: u32,
+    pub channel: u32,
+    //Range of the objects in the channel arguments, see `BMSReader::channel_args`
     args_idx: (usize, usize),
 }
 
@@ -57,7 +72,21 @@ pub struct ImportedBMS {
     pub resource_table: Vec<String>,
     pub title: String,
     pub bpm: f32,
+    pub volwav: u32,
+    pub preview: Option<String>,
+    pub rank: u32,
+    pub defexrank: Option<f64>,
+    pub total: Option<f64>,
+    pub playlevel: Option<u32>,
+    //Hashes of the raw file, see `crate::hash`
+    pub md5: [u8; 16],
+    pub sha256: [u8; 32],
+    //Equal for copies of a chart that only differ in comments and whitespace, see `hash::normalized_hash`
+    pub normalized_hash: [u8; 32],
+    //The file wasn't UTF-8, see `write_bms_bytes`
+    pub shift_jis: bool,
     pub timing: BMSTimings,
+    pub stops: BMSStops,
 }
 
 impl ImportedBMS {
@@ -118,6 +147,67 @@ impl ImportedBMS {
             measure_sets: Rc::new(measure_sets),
         }
     }
+
+    //Lines the importer didn't understand, with their index in the file
+    pub fn unknown_lines(&self) -> Vec<(usize, &str)> {
+        self.cmd_list.iter().enumerate()
+            .filter_map(|(line_idx, cmd)| match cmd {
+                BMSCommand::Other(line) => Some((line_idx, line.as_str())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    //Writes the commands back out as BMS text, in the order they were read or built in.
+    //Lines that weren't understood are written as they were, in the same place.
+    pub fn write_bms(&self) -> String {
+        let mut bms = String::new();
+        let mut base = 36;
+        for cmd in &self.cmd_list {
+            let line = match cmd {
+                BMSCommand::Channel(set) => {
+                    //BPMs on channel 03 were read as base 36 whatever the base
+                    let arg_base = if set.channel == BPM_CHANNEL { 36 } else { base };
+                    let args: String = self.channel_args[set.args_idx.0 .. set.args_idx.1].iter()
+                        .map(|&arg| write_id(arg, arg_base))
+                        .collect();
+                    format!("#{:03}{}:{}", set.measure, bms_channel_name(set.channel), args)
+                },
+                BMSCommand::WAVResource { idx, path } => format!("#WAV{} {}", write_id(*idx, base), path),
+                BMSCommand::ExtendedBPM { idx, bpm } => format!("#BPM{} {}", write_id(*idx, base), bpm),
+                BMSCommand::StopLength { idx, length } => format!("#STOP{} {}", write_id(*idx, base), length),
+                BMSCommand::MeasureLength { measure, length } => format!("#{:03}02:{}", measure, length),
+                BMSCommand::SongInfo(info) => match info {
+                    BMSSongInfo::Title(title) => format!("#TITLE {}", title),
+                    BMSSongInfo::BPM(bpm) => format!("#BPM {}", bpm),
+                    BMSSongInfo::VolWav(volwav) => format!("#VOLWAV {}", volwav),
+                    BMSSongInfo::Preview(preview) => format!("#PREVIEW {}", preview),
+                    BMSSongInfo::Rank(rank) => format!("#RANK {}", rank),
+                    BMSSongInfo::DefExRank(defexrank) => format!("#DEFEXRANK {}", defexrank),
+                    BMSSongInfo::Total(total) => format!("#TOTAL {}", total),
+                    BMSSongInfo::PlayLevel(playlevel) => format!("#PLAYLEVEL {}", playlevel),
+                },
+                BMSCommand::Base(new_base) => {
+                    base = *new_base;
+                    format!("#BASE {}", new_base)
+                },
+                BMSCommand::Other(line) => line.clone(),
+            };
+            bms += &line;
+            bms += "\n";
+        }
+        bms
+    }
+
+    //`write_bms` in the encoding the chart was read in
+    pub fn write_bms_bytes(&self) -> Vec<u8> {
+        let bms = self.write_bms();
+        if self.shift_jis {
+            encoding_rs::SHIFT_JIS.encode(&bms).0.into_owned()
+        } else {
+            bms.into_bytes()
+        }
+    }
 }
 
 fn eval_ibms<'l>(cmds: &'l [BMSCommand]) -> Vec<&'l ChannelCommandSet> {
@@ -132,39 +222,279 @@ fn eval_ibms<'l>(cmds: &'l [BMSCommand]) -> Vec<&'l ChannelCommandSet> {
 }
 
 pub fn import_bms_from_file(path: &str) -> Result<ImportedBMS, BMSImportError> {
-    let mut file = File::open(path)
+    let file = File::open(path)
         .or_else(|_| Err(BMSImportError::CouldntOpenFile))?;
-    let mut file_str = String::new();
-    file.read_to_string(&mut file_str)
-        .or_else(|_| Err(BMSImportError::ErrorReadingFile))?;
-    import_bms(&file_str)
+    BMSReader::new(BufReader::new(file)).finish()
+}
+
+pub fn import_bms_bytes(raw_bms: &[u8]) -> Result<ImportedBMS, BMSImportError> {
+    BMSReader::new(raw_bms).finish()
 }
 
 pub fn import_bms(raw_bms: &str) -> Result<ImportedBMS, BMSImportError> {
-    let mut cmd_list = Vec::new();
-    let mut channel_args = Vec::new();
+    import_bms_with_dialect(raw_bms, &Dialect::bms())
+}
+
+//Imports BMS variants and formats written like BMS, with the headers and channels `dialect` has
+pub fn import_bms_with_dialect(raw_bms: &str, dialect: &Dialect) -> Result<ImportedBMS, BMSImportError> {
+    BMSReader::with_dialect(raw_bms.as_bytes(), dialect.clone()).finish()
+}
+
+//Parses a chart from any reader a line at a time, without holding the file in memory.
+//Every line gives a command, `BMSCommand::Other` for the ones that aren't understood.
+//Charts are often Shift-JIS rather than UTF-8, so lines that aren't valid UTF-8 are decoded
+//as Shift-JIS, which `write_bms_bytes` encodes back to. The hashes are taken over the bytes as they are.
+//Readers fed text made from some other file skip hashing, see `without_hashes`.
+pub struct BMSReader<R: BufRead> {
+    reader: R,
+    dialect: Dialect,
+    id_base: u32,
+    channel_args: Vec<u32>,
+    line: Vec<u8>,
+    shift_jis: bool,
+    hasher: Option<hash::ChartHasher>,
+}
+
+impl<R: BufRead> BMSReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self::with_dialect(reader, Dialect::bms())
+    }
+    pub fn with_dialect(reader: R, dialect: Dialect) -> Self {
+        Self {
+            reader,
+            id_base: dialect.id_base,
+            dialect,
+            channel_args: Vec::new(),
+            line: Vec::new(),
+            shift_jis: false,
+            hasher: Some(hash::ChartHasher::default()),
+        }
+    }
+    //For text converted from another format, whose hashes are taken over the original file.
+    //The chart's hashes are left zeroed.
+    pub fn without_hashes(mut self) -> Self {
+        self.hasher = None;
+        self
+    }
+    //None at the end of the file
+    pub fn next_command(&mut self) -> Result<Option<BMSCommand>, BMSImportError> {
+        self.line.clear();
+        let read = self.reader.read_until(b'\n', &mut self.line)
+            .map_err(|_| BMSImportError::ErrorReadingFile)?;
+        if read == 0 { return Ok(None); }
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update_line(&self.line);
+        }
+        let line = match std::str::from_utf8(&self.line) {
+            Ok(line) => Cow::Borrowed(line),
+            Err(_) => {
+                self.shift_jis = true;
+                encoding_rs::SHIFT_JIS.decode_without_bom_handling(&self.line).0
+            },
+        };
+        let line = line.strip_suffix('\n').unwrap_or(&line);
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        let cmd = parse_bmscript_line(line, &mut self.channel_args, &self.dialect, &mut self.id_base)?
+            .unwrap_or_else(|| BMSCommand::Other(line.to_string()));
+        Ok(Some(cmd))
+    }
+    //Objects of a channel command this reader gave
+    pub fn channel_args(&self, set: &ChannelCommandSet) -> &[u32] {
+        &self.channel_args[set.args_idx.0 .. set.args_idx.1]
+    }
+    //Reads the rest of the file into a chart
+    pub fn finish(mut self) -> Result<ImportedBMS, BMSImportError> {
+        let mut cmd_list = Vec::new();
+        while let Some(cmd) = self.next_command()? {
+            cmd_list.push(cmd);
+        }
+        let mut bms = make_imported_bms(cmd_list, self.channel_args);
+        bms.shift_jis = self.shift_jis;
+        if let Some(hasher) = self.hasher {
+            (bms.md5, bms.sha256, bms.normalized_hash) = hasher.finish();
+        }
+        Ok(bms)
+    }
+    //Parses only up to the first channel command, for getting the title, level and such of many
+    //charts quickly. The chart has no objects. The rest of the file is hashed without being parsed,
+    //so the hashes are those of the whole file.
+    pub fn read_header(mut self) -> Result<ImportedBMS, BMSImportError> {
+        let mut cmd_list = Vec::new();
+        while let Some(cmd) = self.next_command()? {
+            if let BMSCommand::Channel(_) = cmd { break; }
+            cmd_list.push(cmd);
+        }
+        let mut header = make_imported_bms(cmd_list, Vec::new());
+        header.shift_jis = self.shift_jis;
+        if let Some(mut hasher) = self.hasher {
+            loop {
+                self.line.clear();
+                let read = self.reader.read_until(b'\n', &mut self.line)
+                    .map_err(|_| BMSImportError::ErrorReadingFile)?;
+                if read == 0 { break; }
+                hasher.update_line(&self.line);
+            }
+            (header.md5, header.sha256, header.normalized_hash) = hasher.finish();
+        }
+        Ok(header)
+    }
+}
+
+impl<R: BufRead> Iterator for BMSReader<R> {
+    type Item = Result<BMSCommand, BMSImportError>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_command().transpose()
+    }
+}
+
+//BPM of charts without a #BPM header
+pub const DEFAULT_BPM: f32 = 130.0;
+
+//Everything but the hashes, which depend on where the commands came from
+fn make_imported_bms(cmd_list: Vec<BMSCommand>, channel_args: Vec<u32>) -> ImportedBMS {
     let mut title = String::new();
-    let mut bpm = 0.0;
-    for line in raw_bms.lines() {
-        if let Some(cmd) = parse_bmscript_line(line, &mut channel_args)? {
-            if let BMSCommand::SongInfo(ref sinfo) = &cmd {
-                match sinfo {
-                    BMSSongInfo::Title(t) => title = t.clone(),
-                    BMSSongInfo::BPM(b) => bpm = *b,
-                }
+    let mut bpm = DEFAULT_BPM;
+    let mut volwav = 100;
+    let mut preview = None;
+    let mut rank = 2;
+    let mut defexrank = None;
+    let mut total = None;
+    let mut playlevel = None;
+    for cmd in &cmd_list {
+        if let BMSCommand::SongInfo(sinfo) = cmd {
+            match sinfo {
+                BMSSongInfo::Title(t) => title = t.clone(),
+                BMSSongInfo::BPM(b) => bpm = *b,
+                BMSSongInfo::VolWav(v) => volwav = *v,
+                BMSSongInfo::Preview(p) => preview = Some(p.clone()),
+                BMSSongInfo::Rank(r) => rank = *r,
+                BMSSongInfo::DefExRank(r) => defexrank = Some(*r),
+                BMSSongInfo::Total(t) => total = Some(*t),
+                BMSSongInfo::PlayLevel(l) => playlevel = Some(*l),
             }
-            cmd_list.push(cmd);
         }
     }
     let resource_table = make_bms_resource_table(&cmd_list);
-    Ok(ImportedBMS {
+    let (timing, stops) = make_bms_timing(&cmd_list, &channel_args, bpm);
+    ImportedBMS {
         cmd_list,
         channel_args,
         resource_table,
         title,
         bpm,
-        timing: vec![(BMSTime::from(0.0), bpm, 4)], //THIS IS A PLACEHOLDER VALUE, true for most charts tho.
-    })
+        volwav,
+        preview,
+        rank,
+        defexrank,
+        total,
+        playlevel,
+        md5: [0; 16],
+        sha256: [0; 32],
+        normalized_hash: [0; 32],
+        shift_jis: false,
+        timing,
+        stops,
+    }
+}
+
+//Keysound of notes converted from formats without keysounds, left undefined so they make no sound
+pub const SILENT_KEYSOUND: u32 = 1295;
+//Slots per measure `beat_position` rounds to
+pub const BEAT_POSITION_RESOLUTION: u32 = 9600;
+
+//Measure and position in it of a beat, for formats timed in 4/4 beats rather than measures
+pub fn beat_position(beat: f64) -> (u32, u32, u32) {
+    let slot = (beat.max(0.0) / 4.0 * BEAT_POSITION_RESOLUTION as f64).round() as u32;
+    (slot / BEAT_POSITION_RESOLUTION, slot % BEAT_POSITION_RESOLUTION, BEAT_POSITION_RESOLUTION)
+}
+
+//Builds charts from other formats out of the same commands BMS files are parsed
+//into, so they get compiled and timed exactly like BMS charts
+#[derive(Clone, Debug, Default)]
+pub struct ChartBuilder {
+    cmd_list: Vec<BMSCommand>,
+    //(measure, numerator, denominator, channel, value)
+    objects: Vec<(u32, u32, u32, u32, u32)>,
+    ext_bpm_cnt: u32,
+    stop_cnt: u32,
+}
+
+impl ChartBuilder {
+    pub fn new(bpm: f32) -> Self {
+        let mut builder = Self::default();
+        builder.cmd_list.push(BMSCommand::SongInfo(BMSSongInfo::BPM(bpm)));
+        builder
+    }
+    pub fn set_title(&mut self, title: &str) {
+        self.cmd_list.push(BMSCommand::SongInfo(BMSSongInfo::Title(title.to_string())));
+    }
+    pub fn set_playlevel(&mut self, playlevel: u32) {
+        self.cmd_list.push(BMSCommand::SongInfo(BMSSongInfo::PlayLevel(playlevel)));
+    }
+    pub fn set_rank(&mut self, rank: u32) {
+        self.cmd_list.push(BMSCommand::SongInfo(BMSSongInfo::Rank(rank)));
+    }
+    pub fn set_total(&mut self, total: f64) {
+        self.cmd_list.push(BMSCommand::SongInfo(BMSSongInfo::Total(total)));
+    }
+    pub fn set_keysound(&mut self, idx: u32, path: &str) {
+        self.cmd_list.push(BMSCommand::WAVResource { idx, path: path.to_string() });
+    }
+    pub fn set_measure_length(&mut self, measure: u32, length: f64) {
+        self.cmd_list.push(BMSCommand::MeasureLength { measure, length });
+    }
+    //Places `value` on `channel`, `num / den` of the way into `measure`
+    pub fn add_object(&mut self, measure: u32, num: u32, den: u32, channel: u32, value: u32) {
+        if den == 0 || value == 0 { return; }
+        let divisor = num::integer::gcd(num, den);
+        let (num, den) = (num / divisor, den / divisor);
+        self.objects.push((measure + num / den, num % den, den, channel, value));
+    }
+    pub fn add_bpm_change(&mut self, measure: u32, num: u32, den: u32, bpm: f32) {
+        self.ext_bpm_cnt += 1;
+        self.cmd_list.push(BMSCommand::ExtendedBPM { idx: self.ext_bpm_cnt, bpm });
+        self.add_object(measure, num, den, EXT_BPM_CHANNEL, self.ext_bpm_cnt);
+    }
+    //Stop length in 1/192 of a 4/4 measure, like #STOPxx
+    pub fn add_stop(&mut self, measure: u32, num: u32, den: u32, length: f64) {
+        self.stop_cnt += 1;
+        self.cmd_list.push(BMSCommand::StopLength { idx: self.stop_cnt, length });
+        self.add_object(measure, num, den, STOP_CHANNEL, self.stop_cnt);
+    }
+    pub fn build(mut self) -> ImportedBMS {
+        let mut channel_args = Vec::new();
+        self.objects.sort_by_key(|&(measure, _, _, channel, _)| (measure, channel));
+        let mut beg = 0;
+        while beg < self.objects.len() {
+            let (measure, _, _, channel, _) = self.objects[beg];
+            let end = beg + self.objects[beg ..].iter().take_while(|o| o.0 == measure && o.3 == channel).count();
+            let slots = self.objects[beg .. end].iter().fold(1, |acc, o| lcm(acc, o.2));
+            //Objects sharing a slot go on separate lines, the way BMS files stack BGM
+            let mut layers: Vec<Vec<u32>> = Vec::new();
+            for &(_, num, den, _, value) in &self.objects[beg .. end] {
+                let slot = (num * (slots / den)) as usize;
+                match layers.iter_mut().find(|layer| layer[slot] == 0) {
+                    Some(layer) => layer[slot] = value,
+                    None => {
+                        let mut layer = vec![0; slots as usize];
+                        layer[slot] = value;
+                        layers.push(layer);
+                    },
+                }
+            }
+            for layer in layers {
+                let args_beg = channel_args.len();
+                channel_args.extend(layer);
+                self.cmd_list.push(BMSCommand::Channel(ChannelCommandSet {
+                    measure,
+                    channel,
+                    args_idx: (args_beg, channel_args.len()),
+                }));
+            }
+            beg = end;
+        }
+        make_imported_bms(self.cmd_list, channel_args)
+    }
 }
 
 fn make_bms_resource_table<'s>(cmd_list: &'s Vec<BMSCommand>) -> Vec<String> {
@@ -185,74 +515,322 @@ fn make_bms_resource_table<'s>(cmd_list: &'s Vec<BMSCommand>) -> Vec<String> {
     paths
 }
 
-fn parse_bmscript_line(line: &str, channel_args: &mut Vec<u32>) -> Result<Option<BMSCommand>, BMSImportError> {
+//Builds BPM segments from #BPM, channels 02, 03 and 08, and stops from channel 09
+fn make_bms_timing(cmd_list: &[BMSCommand], channel_args: &[u32], bpm: f32) -> (BMSTimings, BMSStops) {
+    let mut ext_bpms = HashMap::new();
+    let mut stop_lengths = HashMap::new();
+    let mut measure_lengths = HashMap::new();
+    let mut last_measure = 0;
+    for cmd in cmd_list {
+        match cmd {
+            BMSCommand::ExtendedBPM { idx, bpm } => { ext_bpms.insert(*idx, *bpm); },
+            BMSCommand::StopLength { idx, length } => { stop_lengths.insert(*idx, *length); },
+            BMSCommand::MeasureLength { measure, length } => {
+                measure_lengths.insert(*measure, *length);
+                last_measure = last_measure.max(*measure);
+            },
+            BMSCommand::Channel(set) => last_measure = last_measure.max(set.measure),
+            _ => (),
+        }
+    }
+    let mut bpm_changes = Vec::new();
+    let mut stop_positions = Vec::new();
+    for cmd in cmd_list {
+        if let BMSCommand::Channel(set) = cmd {
+            let args = &channel_args[set.args_idx.0 .. set.args_idx.1];
+            for (i, &value) in args.iter().enumerate() {
+                if value == 0 { continue; }
+                let pos = BMSTime::from(set.measure as f64 + i as f64 / args.len() as f64);
+                match set.channel {
+                    //Channel 03 holds hexadecimal BPM values, but was read as base 36
+                    BPM_CHANNEL => bpm_changes.push((pos, ((value / 36) * 16 + value % 36) as f32)),
+                    EXT_BPM_CHANNEL => if let Some(bpm) = ext_bpms.get(&value) {
+                        bpm_changes.push((pos, *bpm));
+                    },
+                    STOP_CHANNEL => if let Some(length) = stop_lengths.get(&value) {
+                        stop_positions.push((pos, *length));
+                    },
+                    _ => (),
+                }
+            }
+        }
+    }
+    bpm_changes.retain(|(_, bpm)| *bpm > 0.0);
+    bpm_changes.sort_by(|a, b| f64::from(a.0).total_cmp(&f64::from(b.0)));
+    stop_positions.sort_by(|a, b| f64::from(a.0).total_cmp(&f64::from(b.0)));
+    //Stops are measured in 1/192 of a 4/4 measure at the BPM they happen at
+    let bpm_at = |pos: BMSTime| bpm_changes.iter()
+        .take_while(|(change_pos, _)| *change_pos <= pos)
+        .last()
+        .map_or(bpm, |(_, bpm)| *bpm);
+    let stops = stop_positions.iter()
+        .map(|(pos, length)| (*pos, *length * 240.0 / 192.0 / bpm_at(*pos) as f64))
+        .collect();
+    let mut timings = BMSTimings::new();
+    let mut current_bpm = bpm;
+    let mut changes = bpm_changes.iter().peekable();
+    let mut prev_length = None;
+    for measure in 0 ..= last_measure {
+        let length = measure_lengths.get(&measure).cloned().unwrap_or(1.0);
+        let beats = length * 4.0;
+        let measure_beg = BMSTime::from(measure as f64);
+        let measure_end = BMSTime::from(measure as f64 + 1.0);
+        if prev_length != Some(length) {
+            push_timing(&mut timings, (measure_beg, current_bpm, beats));
+        }
+        while let Some((pos, bpm)) = changes.next_if(|(pos, _)| *pos < measure_end) {
+            current_bpm = *bpm;
+            push_timing(&mut timings, (*pos, current_bpm, beats));
+        }
+        prev_length = Some(length);
+    }
+    (timings, stops)
+}
+
+//Segments at the same position would have zero length, the later one wins
+fn push_timing(timings: &mut BMSTimings, timing: (BMSTime, f32, f64)) {
+    match timings.last_mut() {
+        Some(last) if last.0 == timing.0 => *last = timing,
+        _ => timings.push(timing),
+    }
+}
+
+//Leading number of header values, which can be followed by anything.
+//None when there's none or it doesn't fit `T`, like #PLAYLEVEL 3.5, and the line is left out.
+fn header_number<T: FromStr>(value: &str) -> Result<Option<T>, BMSImportError> {
+    let end = value.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(value.len());
+    Ok(value[.. end].parse().ok())
+}
+
+fn parse_bmscript_line(line: &str, channel_args: &mut Vec<u32>, dialect: &Dialect, id_base: &mut u32) -> Result<Option<BMSCommand>, BMSImportError> {
+    let line = line.trim();
     //Capture channel commands
     if let Some(captures) = CHANNEL_CMD_REGEX.captures(line) {
-        //println!("dziad");
-        let args_beg = channel_args.len();
-        let mut args_cnt = 0;
         let measure = u32::from_str(captures.name("measure").unwrap().as_str())
-            .or_else(|_| Err(BMSImportError::NumericFormatError))?;
-        let channel = u32::from_str(captures.name("channel").unwrap().as_str())
-            .or_else(|_| Err(BMSImportError::NumericFormatError))?;
-        let indices_str = captures.name("indices").unwrap().as_str();
-        //println!("ind: {}", indices_str);
-        push_indices_from_str_to_arglist(indices_str, channel_args, &mut args_cnt)?;
-        let channel_cmd = BMSCommand::Channel(ChannelCommandSet{
+            .map_err(|_| BMSImportError::NumericFormatError)?;
+        let channel = match dialect.channel(captures.name("channel").unwrap().as_str()) {
+            Some(channel) => channel,
+            //Channels we can't represent are ignored, like any other unknown line
+            None => return Ok(None),
+        };
+        let data = captures.name("data").unwrap().as_str().trim();
+        //Measure lengths, which unlike other channels hold a decimal number
+        if channel == MEASURE_LENGTH_CHANNEL {
+            return Ok(header_number(data)?.map(|length| BMSCommand::MeasureLength { measure, length }));
+        }
+        let args_beg = channel_args.len();
+        let indices_str = &data[.. data.find(|c: char| !c.is_ascii_alphanumeric()).unwrap_or(data.len())];
+        //BPMs on channel 03 are hexadecimal whatever the base, they're read as base 36 and converted later
+        let base = if channel == BPM_CHANNEL { 36 } else { *id_base };
+        push_indices_from_str_to_arglist(indices_str, base, channel_args)?;
+        return Ok(Some(BMSCommand::Channel(ChannelCommandSet {
             measure,
             channel,
-            args_idx: (args_beg, args_beg + args_cnt)
-        });
-        return Ok(Some(channel_cmd));
-    //Capture WAV resource definitions
-    } else if let Some(captures) = WAV_REGEX.captures(line) {
-        let idx = from_base36(captures.name("idx").unwrap().as_str().chars())
-            .or_else(|_| Err(BMSImportError::NumericFormatError))?;
-        let path = captures.name("path").unwrap().as_str();
-        return Ok(Some(BMSCommand::WAVResource {
-            idx,
-            path: path.to_string(),
-        }));
-    //Capture song title
-    } else if let Some(captures) = HEADER_TITLE_REGEX.captures(line) {
-        let title = captures.name("title").unwrap().as_str().to_string();
-        return Ok(Some(BMSCommand::SongInfo(BMSSongInfo::Title(title))));
-    //Capture song BPM
-    } else if let Some(captures) = BPM_REGEX.captures(line) {
-        let bpm = f32::from_str(captures.name("bpm").unwrap().as_str())
-            .or_else(|_| Err(BMSImportError::NumericFormatError))?;
-        return Ok(Some(BMSCommand::SongInfo(BMSSongInfo::BPM(bpm))));
+            args_idx: (args_beg, channel_args.len()),
+        })));
+    //Capture headers the dialect knows
+    } else if let Some(captures) = HEADER_REGEX.captures(line) {
+        let name = captures.name("name").unwrap().as_str();
+        let value = captures.name("value").map_or("", |value| value.as_str().trim());
+        let (header, id) = match dialect.header(name) {
+            Some((header, id)) => (header, id),
+            None => return Ok(None),
+        };
+        let id = match id {
+            Some(id) => Some(parse_id(id, *id_base).ok_or(BMSImportError::NumericFormatError)?),
+            None => None,
+        };
+        let info = |info: Option<BMSSongInfo>| Ok(info.map(BMSCommand::SongInfo));
+        return match (header, id) {
+            (Header::Title, _) => info(Some(BMSSongInfo::Title(value.to_string()))),
+            //Either a start time in seconds or an audio file
+            (Header::Preview, _) => info(Some(BMSSongInfo::Preview(value.to_string()))),
+            (Header::Bpm, _) => info(header_number(value)?.map(BMSSongInfo::BPM)),
+            (Header::VolWav, _) => info(header_number(value)?.map(BMSSongInfo::VolWav)),
+            (Header::Rank, _) => info(header_number(value)?.map(BMSSongInfo::Rank)),
+            (Header::DefExRank, _) => info(header_number(value)?.map(BMSSongInfo::DefExRank)),
+            (Header::Total, _) => info(header_number(value)?.map(BMSSongInfo::Total)),
+            (Header::PlayLevel, _) => info(header_number(value)?.map(BMSSongInfo::PlayLevel)),
+            (Header::Base, _) => {
+                match header_number(value)? {
+                    Some(base) if (2 ..= 62).contains(&base) => {
+                        *id_base = base;
+                        Ok(Some(BMSCommand::Base(base)))
+                    },
+                    _ => Ok(None),
+                }
+            },
+            (Header::Wav, Some(idx)) => Ok(Some(BMSCommand::WAVResource { idx, path: value.to_string() })),
+            (Header::ExtendedBpm, Some(idx)) => Ok(header_number(value)?.map(|bpm| BMSCommand::ExtendedBPM { idx, bpm })),
+            //Stop length in 1/192 of a 4/4 measure
+            (Header::Stop, Some(idx)) => Ok(header_number(value)?.map(|length| BMSCommand::StopLength { idx, length })),
+            _ => Ok(None),
+        };
     }
     Ok(None)
 }
 
-fn push_indices_from_str_to_arglist(indices_str: &str, args: &mut Vec<u32>, args_cnt: &mut usize) -> Result<(), BMSImportError> {
-    let mut a_iter = indices_str.chars().step_by(2);
-    let mut b_iter = indices_str.chars().skip(1).step_by(2);
-    while let (Some(a), Some(b)) = (a_iter.next(), b_iter.next()) {
-        let b36num_str = [a, b];
-        let num = from_base36(b36num_str.iter().cloned())
-            .or_else(|_| Err(BMSImportError::InvalidBase36Format))?;
-        args.push(num);
-        *args_cnt += 1;
+fn push_indices_from_str_to_arglist(indices_str: &str, base: u32, args: &mut Vec<u32>) -> Result<(), BMSImportError> {
+    let chars: Vec<char> = indices_str.chars().collect();
+    for pair in chars.chunks_exact(2) {
+        let id: String = pair.iter().collect();
+        args.push(parse_id(&id, base).ok_or(BMSImportError::InvalidBase36Format)?);
     }
     Ok(())
 }
 
-fn from_base36<'a, I>(numstr: I) -> Result<u32, ()> where I: IntoIterator<Item = char> {
-    let mut v = 0;
-    let iter = numstr.into_iter();
-    for c in iter {
-        v *= 36;
-        if c >= '0' && c <= '9' {
-            v += c as u32 - '0' as u32;
-        } else if c >= 'A' && c <= 'Z' {
-            v += c as u32 - 'A' as u32 + 10;
-        } else if c >= 'a' && c<= 'z' {
-            v += c as u32 - 'a' as u32 + 10;
-        } else {
-            return Err(());
+//Inverse of `parse_id`, at least two digits long
+fn write_id(value: u32, base: u32) -> String {
+    let digit = |d: u32| match d {
+        0 ..= 35 => std::char::from_digit(d, 36).unwrap().to_ascii_uppercase(),
+        _ => (b'a' + (d - 36) as u8) as char,
+    };
+    match value {
+        _ if value < base * base => format!("{}{}", digit(value / base), digit(value % base)),
+        _ => write_id(value / base, base) + &digit(value % base).to_string(),
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_import_bms_timing() {
+    let bms = import_bms("#BPM 120\n#BPM01 150.5\n#STOP01 48\n#00102:0.75\n#00208:0001\n#00209:01\n#00311:01").unwrap();
+    assert_eq!(bms.timing, vec![
+        (0.0.into(), 120.0, 4.0),
+        (1.0.into(), 120.0, 3.0),
+        (2.0.into(), 120.0, 4.0),
+        (2.5.into(), 150.5, 4.0),
+    ]);
+    assert_eq!(bms.stops, vec![(2.0.into(), 0.5)]);
+    //Measures that aren't a whole number of beats keep the real BPM
+    let bms = import_bms("#BPM 120\n#00102:0.3\n#00211:01").unwrap();
+    assert_eq!(bms.timing[1], (1.0.into(), 120.0, 0.3 * 4.0));
+    assert_eq!(BMSTime::from(2.0).to_absolute_time(&bms.timing, None), 2.6);
+    //No #BPM means 130
+    assert_eq!(import_bms("#00011:01").unwrap().timing, vec![(0.0.into(), DEFAULT_BPM, 4.0)]);
+}
+
+#[cfg(test)]
+#[test]
+fn test_import_bms_bytes_hashes() {
+    //Shift-JIS title, not valid UTF-8
+    let raw: &[u8] = b"#TITLE \x83\x65\x83\x58\x83\x67\n#BPM 120\n#00111:01\n";
+    let bms = import_bms_bytes(raw).unwrap();
+    assert_eq!(bms.md5, hash::md5(raw));
+    assert_eq!(bms.sha256, hash::sha256(raw));
+    assert_eq!(bms.bpm, 120.0);
+    assert_eq!(import_bms("#BPM 120").unwrap().md5, hash::md5(b"#BPM 120"));
+}
+
+#[cfg(test)]
+#[test]
+fn test_import_bms_normalized_hash() {
+    let a = import_bms_bytes(b"*Song\r\n#TITLE  Song\r\n\r\n#BPM 120\r\n#00111:0101\r\n").unwrap();
+    let b = import_bms_bytes(b"  #TITLE Song\n#BPM\t120\n#00111:0101  \n// another comment\n").unwrap();
+    assert_ne!(a.md5, b.md5);
+    assert_eq!(a.normalized_hash, b.normalized_hash);
+    assert_ne!(a.normalized_hash, import_bms("#TITLE Song\n#BPM 120\n#00111:0100").unwrap().normalized_hash);
+}
+
+#[cfg(test)]
+#[test]
+fn test_write_bms() {
+    let raw = "#TITLE Song\n#BPM 120\n#WAVZZ kick.wav\n#BPM01 150.5\n#STOP01 12.5\n#00102:0.75\n#00103:78\n#00109:0001\n#001D1:0Z\n#00211:01ZZ\n";
+    let bms = import_bms(raw).unwrap();
+    assert_eq!(bms.write_bms(), raw);
+    let mut builder = ChartBuilder::new(140.0);
+    builder.add_object(0, 1, 3, 11, 1);
+    builder.add_object(0, 1, 2, 11, 2);
+    builder.add_object(0, 1, 2, 11, 3);
+    builder.add_stop(1, 0, 1, 96.0);
+    assert_eq!(builder.build().write_bms(), "#BPM 140\n#STOP01 96\n#00011:000001020000\n#00011:000000030000\n#00109:01\n");
+}
+
+#[cfg(test)]
+#[test]
+fn test_import_bms_with_dialect() {
+    let raw = "#BASE 62\n#WAVaz kick.wav\n#DIFFICULTY 5\n#00111:az00\n#00113:az\n#0015A:01\n";
+    //Plain BMS doesn't know #BASE, so az is 10 * 36 + 35
+    let bms = import_bms(raw).unwrap();
+    assert_eq!(bms.resource_table[10 * 36 + 35], "kick.wav");
+    let mut dialect = Dialect::beatoraja();
+    dialect.register_header("DIFFICULTY", Header::PlayLevel);
+    dialect.register_channel("13", None);
+    dialect.register_channel("5A", Some(MINE_CHANNEL));
+    let bms = import_bms_with_dialect(raw, &dialect).unwrap();
+    let az = 36 * 62 + 61;
+    assert_eq!((bms.resource_table[az].as_str(), bms.playlevel), ("kick.wav", Some(5)));
+    let channels: Vec<(u32, u32)> = bms.cmd_list.iter()
+        .filter_map(|cmd| match cmd {
+            BMSCommand::Channel(set) => Some((set.channel, bms.channel_args[set.args_idx.0])),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(channels, vec![(11, az as u32), (MINE_CHANNEL, 1)]);
+}
+
+#[cfg(test)]
+#[test]
+fn test_import_bms_odd_header_numbers() {
+    //Numbers that don't fit the header are left out instead of failing the import
+    let bms = import_bms_with_dialect("#PLAYLEVEL 3.5\n#RANK 2.0\n#BASE 99\n#BPM 150\n#00111:0Z\n", &Dialect::beatoraja()).unwrap();
+    assert_eq!((bms.playlevel, bms.rank, bms.bpm), (None, 2, 150.0));
+    assert_eq!(bms.channel_args, vec![35]);
+    //They stay in the file as they were
+    assert_eq!(bms.write_bms(), "#PLAYLEVEL 3.5\n#RANK 2.0\n#BASE 99\n#BPM 150\n#00111:0Z\n");
+}
+
+#[cfg(test)]
+#[test]
+fn test_write_bms_keeps_unknown_lines() {
+    let raw = "*---------------------- HEADER FIELD\n#ARTIST Someone\n#TITLE Song\n\n#RANDOM 2\n#IF 1\n#00111:01\n#ENDIF\n#BASE 62\n#WAVaz kick.wav\n#00112:az00\n#00103:7F\n#001A3:01\n";
+    let bms = import_bms_with_dialect(raw, &Dialect::beatoraja()).unwrap();
+    assert_eq!(bms.unknown_lines(), vec![(0, "*---------------------- HEADER FIELD"), (1, "#ARTIST Someone"), (3, ""), (4, "#RANDOM 2"), (5, "#IF 1"), (7, "#ENDIF")]);
+    assert_eq!(bms.write_bms(), raw);
+    //Channels without a meaning here are kept as channels, and unknown lines don't end up in the chart
+    assert_eq!(bms.eval_and_compile().playable_notes(&bms.timing, &bms.stops).len(), 2);
+}
+
+#[cfg(test)]
+#[test]
+fn test_bms_reader() {
+    let raw = "#TITLE Streamed\r\n#PLAYLEVEL 7\r\n#WAV01 kick.wav\r\n#00111:0001\r\n#SUBTITLE after the objects\r\n#00211:01\r\n";
+    let mut reader = BMSReader::new(raw.as_bytes());
+    let mut channels = Vec::new();
+    while let Some(cmd) = reader.next_command().unwrap() {
+        if let BMSCommand::Channel(set) = cmd {
+            channels.push((set.measure, set.channel, reader.channel_args(&set).to_vec()));
         }
     }
-    Ok(v)
-}
\ No newline at end of file
+    assert_eq!(channels, vec![(1, 11, vec![0, 1]), (2, 11, vec![1])]);
+    assert_eq!(BMSReader::new(raw.as_bytes()).filter(|cmd| matches!(cmd, Ok(BMSCommand::Other(_)))).count(), 1);
+    let header = BMSReader::new(std::io::BufReader::with_capacity(4, raw.as_bytes())).read_header().unwrap();
+    assert_eq!((header.title.as_str(), header.playlevel, header.resource_table[1].as_str()), ("Streamed", Some(7), "kick.wav"));
+    assert_eq!((header.md5, header.sha256), (hash::md5(raw.as_bytes()), hash::sha256(raw.as_bytes())));
+    assert_eq!(header.normalized_hash, hash::normalized_hash(raw));
+    assert_eq!(header.eval_and_compile().playable_notes(&header.timing, &header.stops).len(), 0);
+    let bms = BMSReader::new(std::io::BufReader::with_capacity(4, raw.as_bytes())).finish().unwrap();
+    assert_eq!((bms.md5, bms.sha256), (hash::md5(raw.as_bytes()), hash::sha256(raw.as_bytes())));
+    assert_eq!(bms.write_bms(), raw.replace("\r\n", "\n"));
+}
+
+#[cfg(test)]
+#[test]
+fn test_bms_reader_without_hashes() {
+    let bms = BMSReader::new("#BPM 150".as_bytes()).without_hashes().finish().unwrap();
+    assert_eq!((bms.bpm, bms.md5, bms.sha256, bms.normalized_hash), (150.0, [0; 16], [0; 32], [0; 32]));
+}
+
+#[cfg(test)]
+#[test]
+fn test_write_bms_shift_jis() {
+    let (raw, _, _) = encoding_rs::SHIFT_JIS.encode("#TITLE 曲名\n#ARTIST 作曲者\n#WAV01 キック.wav\n*コメント\n#00111:01\n");
+    let bms = import_bms_bytes(&raw).unwrap();
+    assert_eq!((bms.title.as_str(), bms.resource_table[1].as_str(), bms.shift_jis), ("曲名", "キック.wav", true));
+    assert_eq!(bms.unknown_lines(), vec![(1, "#ARTIST 作曲者"), (3, "*コメント")]);
+    assert_eq!(bms.write_bms_bytes(), raw.into_owned());
+    //Comments aren't hashed whatever their encoding
+    let (commented, _, _) = encoding_rs::SHIFT_JIS.encode("#TITLE 曲名\n#ARTIST 作曲者\n#WAV01 キック.wav\n*別のコメント\n#00111:01\n");
+    assert_eq!(import_bms_bytes(&commented).unwrap().normalized_hash, bms.normalized_hash);
+    assert!(!import_bms("#TITLE 曲名").unwrap().shift_jis);
+}
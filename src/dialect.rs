@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+
+//What a recognised header sets
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum Header {
+    Title,
+    Bpm,
+    VolWav,
+    Preview,
+    Rank,
+    DefExRank,
+    Total,
+    PlayLevel,
+    //Switches the base object ids are written in for the rest of the file
+    Base,
+    //Headers followed by an object id, like #WAV01
+    Wav,
+    ExtendedBpm,
+    Stop,
+}
+
+impl Header {
+    pub fn is_indexed(&self) -> bool {
+        matches!(self, Header::Wav | Header::ExtendedBpm | Header::Stop)
+    }
+}
+
+//Everything the importer needs to know about a format written like BMS: the headers it has,
+//the channel each channel of the format behaves like and the base object ids are written in.
+//Channel numbers are the ones BMS uses (see `ChannelKind`), with letter channels like D1 taken
+//as their base 36 first character times ten plus the digit, D1 being 131.
+#[derive(Clone, Debug)]
+pub struct Dialect {
+    pub name: String,
+    //Names without the #, uppercase
+    headers: HashMap<String, Header>,
+    //Channels registered one by one, checked before `channel_parser`
+    channels: HashMap<String, Option<u32>>,
+    pub channel_parser: fn(&str) -> Option<u32>,
+    pub id_base: u32,
+}
+
+//Channels written in decimal, or as a letter followed by a digit
+pub fn bms_channel(channel: &str) -> Option<u32> {
+    if let Ok(channel) = channel.parse::<u32>() { return Some(channel); }
+    let mut chars = channel.chars();
+    let tens = chars.next()?.to_digit(36)?;
+    let ones = chars.next()?.to_digit(10)?;
+    Some(tens * 10 + ones)
+}
+
+//Inverse of `bms_channel`
+pub fn bms_channel_name(channel: u32) -> String {
+    match channel {
+        0 ..= 99 => format!("{:02}", channel),
+        _ => format!("{}{}", std::char::from_digit(channel / 10, 36).unwrap_or('Z').to_ascii_uppercase(), channel % 10),
+    }
+}
+
+//Digits of ids in bases up to 36 are case insensitive, base 62 has lowercase letters after the uppercase ones
+pub fn parse_id(id: &str, base: u32) -> Option<u32> {
+    id.chars().try_fold(0, |value, c| {
+        let digit = match (c, base) {
+            ('a' ..= 'z', 37 ..= 62) => c as u32 - 'a' as u32 + 36,
+            _ => c.to_digit(36)?,
+        };
+        if digit >= base { return None; }
+        Some(value * base + digit)
+    })
+}
+
+impl Dialect {
+    pub fn new(name: &str, channel_parser: fn(&str) -> Option<u32>) -> Self {
+        Self {
+            name: name.to_string(),
+            headers: HashMap::new(),
+            channels: HashMap::new(),
+            channel_parser,
+            id_base: 36,
+        }
+    }
+    //BMS as most players read it, which also covers .bme and .bml files
+    pub fn bms() -> Self {
+        let mut dialect = Self::new("BMS", bms_channel);
+        let headers = [
+            ("TITLE", Header::Title),
+            ("BPM", Header::Bpm),
+            ("VOLWAV", Header::VolWav),
+            ("PREVIEW", Header::Preview),
+            ("RANK", Header::Rank),
+            ("DEFEXRANK", Header::DefExRank),
+            ("TOTAL", Header::Total),
+            ("PLAYLEVEL", Header::PlayLevel),
+            ("WAV", Header::Wav),
+            ("BPM", Header::ExtendedBpm),
+            ("STOP", Header::Stop),
+        ];
+        for (name, header) in &headers {
+            dialect.register_header(name, *header);
+        }
+        dialect
+    }
+    //Pop'n Music charts, with the 9 buttons on channels 11-15 and 22-25 (lanes 0-4 and 10-13).
+    //The channels of the other BMS lanes aren't buttons in .pms files, so their notes are left out.
+    pub fn pms() -> Self {
+        let mut dialect = Self::bms();
+        dialect.name = "PMS".to_string();
+        let unused_keys = (6 ..= 9).chain(std::iter::once(11)).chain(16 ..= 19);
+        for key in unused_keys {
+            //Visible, invisible and long note channels, then mines
+            for channel in &[10 + key, 30 + key, 50 + key, crate::cbms::MINE_CHANNEL - 1 + key] {
+                dialect.register_channel(&bms_channel_name(*channel), None);
+            }
+        }
+        dialect
+    }
+    //BMS with beatoraja's additions: base 62 ids
+    pub fn beatoraja() -> Self {
+        let mut dialect = Self::bms();
+        dialect.name = "beatoraja".to_string();
+        dialect.register_header("BASE", Header::Base);
+        dialect
+    }
+    //Headers without an index and indexed ones are told apart, so BPM can be both
+    pub fn register_header(&mut self, name: &str, header: Header) {
+        self.headers.insert(Self::header_key(name, header.is_indexed()), header);
+    }
+    pub fn unregister_header(&mut self, name: &str, indexed: bool) {
+        self.headers.remove(&Self::header_key(name, indexed));
+    }
+    fn header_key(name: &str, indexed: bool) -> String {
+        let name = name.to_ascii_uppercase();
+        if indexed { name + "xx" } else { name }
+    }
+    //Makes `channel` behave like the BMS channel `bms_channel`, or be left out for None
+    pub fn register_channel(&mut self, channel: &str, bms_channel: Option<u32>) {
+        self.channels.insert(channel.to_ascii_uppercase(), bms_channel);
+    }
+    pub fn channel(&self, channel: &str) -> Option<u32> {
+        match self.channels.get(&channel.to_ascii_uppercase()) {
+            Some(registered) => *registered,
+            None => (self.channel_parser)(channel),
+        }
+    }
+    //The header a name (without #) stands for, and the id after it for indexed ones. Names are
+    //case insensitive, ids aren't. Exact names win over indexed ones, so #BPM isn't taken as
+    //BPM with an empty id.
+    pub fn header<'n>(&self, name: &'n str) -> Option<(Header, Option<&'n str>)> {
+        if let Some(header) = self.headers.get(&name.to_ascii_uppercase()) {
+            return Some((*header, None));
+        }
+        let split = name.len().checked_sub(2).filter(|&split| name.is_char_boundary(split))?;
+        let (prefix, id) = name.split_at(split);
+        self.headers.get(&Self::header_key(prefix, true)).map(|header| (*header, Some(id)))
+    }
+}
+
+impl Default for Dialect {
+    fn default() -> Self {
+        Self::bms()
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_dialect() {
+    use crate::cbms::MINE_CHANNEL;
+    let mut dialect = Dialect::beatoraja();
+    assert_eq!(dialect.header("BPM"), Some((Header::Bpm, None)));
+    assert_eq!(dialect.header("BPM0A"), Some((Header::ExtendedBpm, Some("0A"))));
+    assert_eq!(dialect.header("base"), Some((Header::Base, None)));
+    assert_eq!(dialect.header("wavaZ"), Some((Header::Wav, Some("aZ"))));
+    assert_eq!(dialect.header("ARTIST"), None);
+    assert_eq!(dialect.channel("D1"), Some(MINE_CHANNEL));
+    assert_eq!(bms_channel_name(MINE_CHANNEL), "D1");
+    dialect.register_channel("d1", None);
+    dialect.register_channel("5A", Some(MINE_CHANNEL));
+    assert_eq!((dialect.channel("D1"), dialect.channel("5a")), (None, Some(MINE_CHANNEL)));
+    assert_eq!((parse_id("zz", 36), parse_id("zz", 62), parse_id("G0", 16)), (Some(1295), Some(3843), None));
+}
+
+#[cfg(test)]
+#[test]
+fn test_pms_dialect() {
+    use crate::cbms::MINE_CHANNEL;
+    let pms = Dialect::pms();
+    let buttons: Vec<u32> = ["11", "15", "22", "25", "51", "65", "D1", "E5"].iter().filter_map(|c| pms.channel(c)).collect();
+    assert_eq!(buttons, vec![11, 15, 22, 25, 51, 65, MINE_CHANNEL, MINE_CHANNEL + 14]);
+    assert!(["16", "19", "21", "29", "36", "41", "58", "D6", "E1"].iter().all(|c| pms.channel(c).is_none()));
+    assert_eq!(pms.channel("01"), Some(1));
+}
@@ -0,0 +1,164 @@
+use crate::bms::{BMSTimings, BMSStops};
+use crate::cbms::{CBMS, ChannelKind, LANES_PER_SIDE, NoteKind, PlayableNote};
+use crate::cbms::stats::ChartStats;
+
+//Hits on one lane this close make a jack, alternating hits twice as far apart a trill
+pub const JACK_WINDOW: f64 = 0.25;
+
+//Raw measurements the estimate is made from
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct DifficultyFeatures {
+    pub average_nps: f64,
+    pub peak_nps: f64,
+    //Average number of notes hit at once
+    pub chord_size: f64,
+    //Jack and trill notes per second
+    pub jack_rate: f64,
+    pub trill_rate: f64,
+    pub scratch_rate: f64,
+    //Part of the hits that are long notes
+    pub long_note_ratio: f64,
+    pub bpm_changes: usize,
+    pub stops: usize,
+    //Highest BPM divided by the lowest one
+    pub bpm_range: f64,
+}
+
+//How much each feature adds to the rating
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct DifficultyBreakdown {
+    pub density: f64,
+    pub chords: f64,
+    pub jacks: f64,
+    pub trills: f64,
+    pub scratch: f64,
+    pub long_notes: f64,
+    pub gimmicks: f64,
+}
+
+impl DifficultyBreakdown {
+    pub fn total(&self) -> f64 {
+        self.density + self.chords + self.jacks + self.trills + self.scratch + self.long_notes + self.gimmicks
+    }
+}
+
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct DifficultyEstimate {
+    pub features: DifficultyFeatures,
+    pub breakdown: DifficultyBreakdown,
+    //Roughly on the scale of 7 key #PLAYLEVEL
+    pub rating: f64,
+}
+
+impl DifficultyEstimate {
+    pub fn from_features(features: DifficultyFeatures) -> Self {
+        let gimmick_count = (features.bpm_changes + features.stops).min(20) as f64;
+        let breakdown = DifficultyBreakdown {
+            density: features.average_nps * 0.6 + features.peak_nps * 0.2,
+            chords: (features.chord_size - 1.0).max(0.0) * 2.0,
+            jacks: features.jack_rate * 0.5,
+            trills: features.trill_rate * 0.3,
+            scratch: features.scratch_rate * 0.8,
+            long_notes: features.long_note_ratio * 2.0,
+            gimmicks: gimmick_count * 0.05 + features.bpm_range.max(1.0).ln(),
+        };
+        Self {
+            rating: breakdown.total(),
+            features,
+            breakdown,
+        }
+    }
+    //Whether the author's level is further than `tolerance` from the estimate
+    pub fn is_misrated(&self, playlevel: u32, tolerance: f64) -> bool {
+        (self.rating - playlevel as f64).abs() > tolerance
+    }
+}
+
+pub fn estimate_difficulty(cbms: &CBMS, timings: &BMSTimings, stops: &BMSStops) -> DifficultyEstimate {
+    let notes = cbms.playable_notes(timings, stops);
+    let stats = cbms.statistics(timings, stops);
+    let mut features = pattern_features(&notes, &stats);
+    features.bpm_changes = cbms.timed_commands(timings, stops)
+        .iter()
+        .filter(|tc| matches!(tc.command.kind(), ChannelKind::Bpm | ChannelKind::ExtendedBpm))
+        .count();
+    features.stops = stops.len();
+    let bpms = timings.iter().map(|t| t.1 as f64).filter(|&bpm| bpm > 0.0);
+    let (min, max) = bpms.fold((f64::INFINITY, 0.0f64), |(min, max), bpm| (min.min(bpm), max.max(bpm)));
+    features.bpm_range = if max > 0.0 { max / min } else { 1.0 };
+    DifficultyEstimate::from_features(features)
+}
+
+fn pattern_features(notes: &[PlayableNote], stats: &ChartStats) -> DifficultyFeatures {
+    let hits: Vec<&PlayableNote> = notes.iter().filter(|n| n.kind != NoteKind::LongEnd).collect();
+    let mut features = DifficultyFeatures {
+        average_nps: stats.average_nps,
+        peak_nps: stats.peak_nps,
+        ..Default::default()
+    };
+    if hits.is_empty() { return features; }
+    let mut last_hit = vec![f64::NEG_INFINITY; 2 * LANES_PER_SIDE as usize];
+    let (mut chords, mut jacks, mut trills) = (0, 0, 0);
+    //Lane and time of the recent single note hits, for spotting trills
+    let mut singles: Vec<(u32, f64)> = Vec::new();
+    let mut beg = 0;
+    while beg < hits.len() {
+        let time = hits[beg].time;
+        let end = beg + hits[beg ..].iter().take_while(|n| n.time == time).count();
+        chords += 1;
+        for note in &hits[beg .. end] {
+            if time - last_hit[note.lane as usize] <= JACK_WINDOW {
+                jacks += 1;
+            }
+            last_hit[note.lane as usize] = time;
+        }
+        if end - beg == 1 {
+            let lane = hits[beg].lane;
+            if let [.., (a, ta), (b, _)] = singles[..] {
+                if a == lane && b != lane && time - ta <= JACK_WINDOW * 2.0 {
+                    trills += 1;
+                }
+            }
+            singles.push((lane, time));
+        } else {
+            singles.clear();
+        }
+        beg = end;
+    }
+    features.chord_size = hits.len() as f64 / chords as f64;
+    features.long_note_ratio = stats.long_note_count as f64 / hits.len() as f64;
+    if stats.length > 0.0 {
+        features.jack_rate = jacks as f64 / stats.length;
+        features.trill_rate = trills as f64 / stats.length;
+        features.scratch_rate = stats.scratch_count as f64 / stats.length;
+    }
+    features
+}
+
+#[cfg(test)]
+fn test_estimate(raw_bms: &str) -> DifficultyEstimate {
+    let bms = crate::compiler::import_bms(raw_bms).unwrap();
+    estimate_difficulty(&bms.eval_and_compile(), &bms.timing, &bms.stops)
+}
+
+#[cfg(test)]
+#[test]
+fn test_difficulty_features() {
+    //Eighth notes at 120 BPM, as a jack on key 1 and as a trill between keys 1 and 2
+    let jack = test_estimate("#BPM 120\n#00011:0101010101010101\n#00111:01");
+    let trill = test_estimate("#BPM 120\n#00011:0100010001000100\n#00012:0001000100010001\n#00111:01");
+    assert_eq!(jack.features.chord_size, 1.0);
+    assert!(jack.breakdown.jacks > 0.0 && jack.breakdown.trills == 0.0);
+    assert!(trill.breakdown.trills > 0.0 && trill.breakdown.jacks == 0.0);
+    assert_eq!(jack.breakdown.density, trill.breakdown.density);
+    //The same pattern as chords with a BPM change and a stop on top
+    let chords = test_estimate("#BPM 120\n#STOP01 48\n#00011:0101010101010101\n#00013:0101010101010101\n#00103:F0\n#00109:01\n#00111:01\n#00113:01");
+    assert_eq!(chords.features.chord_size, 2.0);
+    assert_eq!((chords.features.bpm_changes, chords.features.stops, chords.features.bpm_range), (1, 1, 2.0));
+    assert!(chords.rating > jack.rating);
+    assert!(chords.is_misrated(1, 2.0));
+    //Odd measure lengths don't change the BPM
+    let odd_measure = test_estimate("#BPM 120\n#00011:01010101\n#00102:0.3\n#00111:01\n#00211:01");
+    assert_eq!((odd_measure.features.bpm_range, odd_measure.breakdown.gimmicks), (1.0, 0.0));
+    assert!(!jack.is_misrated(jack.rating.round() as u32, 0.5));
+}
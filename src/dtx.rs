@@ -0,0 +1,228 @@
+use crate::cbms::{BGM_CHANNEL, BPM_CHANNEL, EXT_BPM_CHANNEL, LANES_PER_SIDE, MEASURE_LENGTH_CHANNEL};
+use crate::compiler::{BMSImportError, BMSReader, ImportedBMS};
+use crate::dialect::{Dialect, Header};
+use crate::hash;
+
+use std::fs::File;
+use std::io::Read;
+
+//Drum kit from left to right, which is also the order of the lanes
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum DrumPad {
+    LeftCymbal,
+    HiHat,
+    LeftPedal,
+    Snare,
+    HighTom,
+    BassDrum,
+    LowTom,
+    FloorTom,
+    Cymbal,
+    Ride,
+}
+
+pub const DRUM_PADS: [DrumPad; 10] = [
+    DrumPad::LeftCymbal,
+    DrumPad::HiHat,
+    DrumPad::LeftPedal,
+    DrumPad::Snare,
+    DrumPad::HighTom,
+    DrumPad::BassDrum,
+    DrumPad::LowTom,
+    DrumPad::FloorTom,
+    DrumPad::Cymbal,
+    DrumPad::Ride,
+];
+
+impl DrumPad {
+    pub fn lane(&self) -> u32 {
+        DRUM_PADS.iter().position(|pad| pad == self).unwrap() as u32
+    }
+    pub fn from_lane(lane: u32) -> Option<Self> {
+        DRUM_PADS.get(lane as usize).copied()
+    }
+    //Closed and open hi-hat share a pad, and so do the left pedal and the left bass drum
+    pub fn from_dtx_channel(channel: u32) -> Option<Self> {
+        match channel {
+            0x11 | 0x18 => Some(DrumPad::HiHat),
+            0x12 => Some(DrumPad::Snare),
+            0x13 => Some(DrumPad::BassDrum),
+            0x14 => Some(DrumPad::HighTom),
+            0x15 => Some(DrumPad::LowTom),
+            0x16 => Some(DrumPad::Cymbal),
+            0x17 => Some(DrumPad::FloorTom),
+            0x19 => Some(DrumPad::Ride),
+            0x1A => Some(DrumPad::LeftCymbal),
+            0x1B | 0x1C => Some(DrumPad::LeftPedal),
+            _ => None,
+        }
+    }
+}
+
+//BMS channel of a drum lane, 11-19 for lanes 0-8 and 21 on for the rest
+fn lane_channel(lane: u32) -> u32 {
+    11 + lane / LANES_PER_SIDE * 10 + lane % LANES_PER_SIDE
+}
+
+//DTX channels are hexadecimal. Drum chips go on their pad's lane and the hidden chips
+//(sounds for hitting a pad with no chip on it) on its invisible channel. The sound effect
+//channels play as BGM. Guitar, bass and everything visual is left out.
+pub fn dtx_channel(channel: &str) -> Option<u32> {
+    let channel = u32::from_str_radix(channel, 16).ok()?;
+    match channel {
+        0x01 | 0x61 ..= 0x69 | 0x70 ..= 0x79 | 0x80 ..= 0x89 | 0x90 ..= 0x92 => Some(BGM_CHANNEL),
+        0x02 => Some(MEASURE_LENGTH_CHANNEL),
+        0x03 => Some(BPM_CHANNEL),
+        0x08 => Some(EXT_BPM_CHANNEL),
+        0x11 ..= 0x1C => DrumPad::from_dtx_channel(channel).map(|pad| lane_channel(pad.lane())),
+        //11 to 31, the invisible channel of the same lane
+        0x31 ..= 0x3C => DrumPad::from_dtx_channel(channel - 0x20).map(|pad| lane_channel(pad.lane()) + 20),
+        _ => None,
+    }
+}
+
+//BMS headers with #DLEVEL, the drum level, as the play level
+pub fn dtx_dialect() -> Dialect {
+    let mut dialect = Dialect::bms();
+    dialect.name = "DTX".to_string();
+    dialect.channel_parser = dtx_channel;
+    dialect.register_header("DLEVEL", Header::PlayLevel);
+    dialect
+}
+
+//Comments start with a ; at the start of the line or after whitespace,
+//so titles and file names can still have one
+fn strip_dtx_comment(line: &str) -> &str {
+    let mut after_space = true;
+    for (idx, c) in line.char_indices() {
+        if c == ';' && after_space { return &line[.. idx]; }
+        after_space = c.is_whitespace();
+    }
+    line
+}
+
+//Rewrites DTX syntax the BMS parser doesn't take: ; comments, headers with a colon after
+//the name and spaces and underscores in channel data
+fn normalize_dtx_line(line: &str) -> Option<String> {
+    let line = strip_dtx_comment(line).trim();
+    if !line.starts_with('#') { return None; }
+    let (name, value) = match line.char_indices().find(|&(_, c)| c == ':' || c.is_whitespace()) {
+        Some((idx, c)) => (&line[.. idx], line[idx + c.len_utf8() ..].trim()),
+        None => (line, ""),
+    };
+    let is_channel = name.chars().count() == 6 && name.chars().skip(1).take(3).all(|c| c.is_ascii_digit());
+    Some(if is_channel {
+        let data: String = value.chars().filter(|c| !c.is_whitespace() && *c != '_').collect();
+        format!("{}:{}", name, data)
+    } else {
+        format!("{} {}", name, value)
+    })
+}
+
+//DTX files are Shift-JIS or UTF-16, the latter with a byte order mark.
+//Files that are valid UTF-8 are read as such.
+fn decode_dtx(raw_dtx: &[u8]) -> String {
+    let utf16 = |bytes: &[u8], from_bytes: fn([u8; 2]) -> u16| {
+        let units: Vec<u16> = bytes.chunks_exact(2).map(|pair| from_bytes([pair[0], pair[1]])).collect();
+        String::from_utf16_lossy(&units)
+    };
+    match raw_dtx {
+        [0xFF, 0xFE, rest @ ..] => utf16(rest, u16::from_le_bytes),
+        [0xFE, 0xFF, rest @ ..] => utf16(rest, u16::from_be_bytes),
+        _ => match std::str::from_utf8(raw_dtx) {
+            Ok(text) => text.to_string(),
+            Err(_) => encoding_rs::SHIFT_JIS.decode_without_bom_handling(raw_dtx).0.into_owned(),
+        },
+    }
+}
+
+pub fn import_dtx_from_file(path: &str) -> Result<ImportedBMS, BMSImportError> {
+    let mut file = File::open(path)
+        .map_err(|_| BMSImportError::CouldntOpenFile)?;
+    let mut raw_dtx = Vec::new();
+    file.read_to_end(&mut raw_dtx)
+        .map_err(|_| BMSImportError::ErrorReadingFile)?;
+    import_dtx_bytes(&raw_dtx)
+}
+
+//Hashed over the bytes of the file, before decoding
+pub fn import_dtx_bytes(raw_dtx: &[u8]) -> Result<ImportedBMS, BMSImportError> {
+    let mut dtx = parse_dtx(&decode_dtx(raw_dtx))?;
+    dtx.md5 = hash::md5(raw_dtx);
+    dtx.sha256 = hash::sha256(raw_dtx);
+    Ok(dtx)
+}
+
+//Drums of a DTX chart on the lanes of `DRUM_PADS`
+pub fn import_dtx(raw_dtx: &str) -> Result<ImportedBMS, BMSImportError> {
+    let mut dtx = parse_dtx(raw_dtx)?;
+    dtx.md5 = hash::md5(raw_dtx.as_bytes());
+    dtx.sha256 = hash::sha256(raw_dtx.as_bytes());
+    Ok(dtx)
+}
+
+//Without the hashes, which the callers take over what they were given
+fn parse_dtx(raw_dtx: &str) -> Result<ImportedBMS, BMSImportError> {
+    let normalized: Vec<String> = raw_dtx.lines().filter_map(normalize_dtx_line).collect();
+    BMSReader::with_dialect(normalized.join("\n").as_bytes(), dtx_dialect()).without_hashes().finish()
+}
+
+#[cfg(test)]
+#[test]
+fn test_import_dtx() {
+    let dtx = import_dtx(concat!(
+        "; Created by hand\n#TITLE: Drum song\n#BPM: 120\n#DLEVEL: 45\n",
+        "#WAV01: snare.wav ; the snare\n#WAV02 kick.wav\n#WAV03: bgm.ogg\n#BPM01: 240\n",
+        "#00001: 03\n#00012: 01 01 01 01\n#00013: 02__00__02__00\n#0001A: 00000001\n#00061: 0001\n",
+        "#00108: 01\n#00118: 01\n#00120: 01\n#00132: 01\n",
+    )).unwrap();
+    assert_eq!((dtx.title.as_str(), dtx.bpm, dtx.playlevel), ("Drum song", 120.0, Some(45)));
+    assert_eq!(&dtx.resource_table[1 ..= 3], &["snare.wav", "kick.wav", "bgm.ogg"]);
+    let cbms = dtx.eval_and_compile();
+    let notes: Vec<(u32, f64, u32)> = cbms.playable_notes(&dtx.timing, &dtx.stops).iter()
+        .map(|n| (n.lane, n.time, n.keysound))
+        .collect();
+    let (snare, kick, cymbal, hihat) = (DrumPad::Snare.lane(), DrumPad::BassDrum.lane(), DrumPad::LeftCymbal.lane(), DrumPad::HiHat.lane());
+    assert_eq!(notes, vec![
+        (snare, 0.0, 1), (kick, 0.0, 2),
+        (snare, 0.5, 1),
+        (snare, 1.0, 1), (kick, 1.0, 2),
+        (snare, 1.5, 1), (cymbal, 1.5, 1),
+        //Measure 1 at 240 BPM, the guitar chip on 20 is left out
+        (hihat, 2.0, 1),
+    ]);
+    let bgm: Vec<(f64, u32)> = cbms.timed_commands(&dtx.timing, &dtx.stops).iter()
+        .filter(|tc| matches!(tc.command.kind(), crate::cbms::ChannelKind::Bgm))
+        .map(|tc| (tc.time, tc.command.value))
+        .collect();
+    assert_eq!(bgm, vec![(0.0, 3), (1.0, 1)]);
+    //The hidden snare chip
+    assert!(cbms.timed_commands(&dtx.timing, &dtx.stops).iter()
+        .any(|tc| matches!(tc.command.kind(), crate::cbms::ChannelKind::Invisible(lane) if lane == snare)));
+    assert_eq!(DrumPad::from_lane(kick), Some(DrumPad::BassDrum));
+}
+
+#[cfg(test)]
+#[test]
+fn test_import_dtx_utf16() {
+    let text = "#TITLE: ドラム\r\n#BPM: 150\r\n#00011: 01\r\n";
+    let mut raw = vec![0xFF, 0xFE];
+    raw.extend(text.encode_utf16().flat_map(u16::to_le_bytes));
+    let dtx = import_dtx_bytes(&raw).unwrap();
+    assert_eq!((dtx.title.as_str(), dtx.bpm), ("ドラム", 150.0));
+    assert_eq!(dtx.md5, hash::md5(&raw));
+}
+
+#[cfg(test)]
+#[test]
+fn test_import_dtx_syntax() {
+    //Shift-JIS, with a full width space after the header name and a ; in the title
+    let (raw, _, _) = encoding_rs::SHIFT_JIS.encode("#TITLE\u{3000}ドラム;2 ; comment\n;#BPM 60\n#BPM: 120\n#00002: 0.75\n#00012: 0101\n#00112: 01\n");
+    let dtx = import_dtx_bytes(&raw).unwrap();
+    assert_eq!((dtx.title.as_str(), dtx.bpm), ("ドラム;2", 120.0));
+    let cbms = dtx.eval_and_compile();
+    let times: Vec<f64> = cbms.playable_notes(&dtx.timing, &dtx.stops).iter().map(|n| n.time).collect();
+    //Measure 0 is 3 beats long
+    assert_eq!(times, vec![0.0, 0.75, 1.5]);
+    assert_eq!(normalize_dtx_line("#０００１２: 01"), Some("#０００１２ 01".to_string()));
+}
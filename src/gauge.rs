@@ -0,0 +1,221 @@
+use crate::cbms::CBMS;
+use crate::compiler::ImportedBMS;
+use crate::judge::{Judgement, JudgeResult};
+use crate::score::ClearLamp;
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum GaugeType {
+    AssistEasy,
+    Easy,
+    Normal,
+    Hard,
+    ExHard,
+    Hazard,
+}
+
+//Gauge change in percent for each judgement
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct GaugeIncrements {
+    pub pgreat: f64,
+    pub great: f64,
+    pub good: f64,
+    pub bad: f64,
+    pub poor: f64,
+    pub empty_poor: f64,
+}
+
+impl GaugeIncrements {
+    pub fn get(&self, judgement: Judgement) -> f64 {
+        match judgement {
+            Judgement::PGreat => self.pgreat,
+            Judgement::Great => self.great,
+            Judgement::Good => self.good,
+            Judgement::Bad => self.bad,
+            Judgement::Poor => self.poor,
+            Judgement::EmptyPoor => self.empty_poor,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct GaugeRules {
+    pub initial: f64,
+    pub minimum: f64,
+    //Gauges with a clear threshold are judged at the end, the others fail on reaching zero
+    pub clear_threshold: Option<f64>,
+    pub increments: GaugeIncrements,
+    //Damage is multiplied by `low_life_factor` while the gauge is below `low_life_threshold`
+    pub low_life_threshold: f64,
+    pub low_life_factor: f64,
+}
+
+impl GaugeType {
+    //Recovery of the groove gauges is driven by #TOTAL spread over all notes
+    pub fn rules(&self, total: f64, note_count: usize) -> GaugeRules {
+        let recovery = total / note_count.max(1) as f64;
+        let groove = |scale: f64, clear: f64| GaugeRules {
+            initial: 20.0,
+            minimum: 2.0,
+            clear_threshold: Some(clear),
+            increments: GaugeIncrements {
+                pgreat: recovery * scale,
+                great: recovery * scale,
+                good: recovery * scale / 2.0,
+                bad: -4.0 / scale,
+                poor: -6.0 / scale,
+                empty_poor: -2.0 / scale,
+            },
+            low_life_threshold: 0.0,
+            low_life_factor: 1.0,
+        };
+        let survival = |bad: f64, poor: f64, empty_poor: f64, low_life_factor: f64| GaugeRules {
+            initial: 100.0,
+            minimum: 0.0,
+            clear_threshold: None,
+            increments: GaugeIncrements {
+                pgreat: 0.1,
+                great: 0.1,
+                good: 0.05,
+                bad,
+                poor,
+                empty_poor,
+            },
+            low_life_threshold: 30.0,
+            low_life_factor,
+        };
+        match self {
+            GaugeType::AssistEasy => groove(1.25, 60.0),
+            GaugeType::Easy => groove(1.25, 80.0),
+            GaugeType::Normal => groove(1.0, 80.0),
+            GaugeType::Hard => survival(-6.0, -10.0, -2.0, 0.6),
+            GaugeType::ExHard => survival(-12.0, -20.0, -10.0, 1.0),
+            GaugeType::Hazard => survival(-100.0, -100.0, -2.0, 1.0),
+        }
+    }
+    pub fn clear_lamp(&self) -> ClearLamp {
+        match self {
+            GaugeType::AssistEasy => ClearLamp::AssistClear,
+            GaugeType::Easy => ClearLamp::EasyClear,
+            GaugeType::Normal => ClearLamp::Clear,
+            GaugeType::Hard => ClearLamp::HardClear,
+            GaugeType::ExHard | GaugeType::Hazard => ClearLamp::ExHardClear,
+        }
+    }
+}
+
+//#TOTAL used by most players when the chart doesn't define one
+pub fn default_total(note_count: usize) -> f64 {
+    let n = note_count as f64;
+    160.0 + (n + (n - 400.0).clamp(0.0, 200.0)) * 0.16
+}
+
+#[derive(Clone, Debug)]
+pub struct Gauge {
+    gauge_type: GaugeType,
+    rules: GaugeRules,
+    value: f64,
+    failed: bool,
+    //(judgement time, gauge value after it)
+    trace: Vec<(f64, f64)>,
+}
+
+impl Gauge {
+    pub fn new(gauge_type: GaugeType, total: f64, note_count: usize) -> Self {
+        let rules = gauge_type.rules(total, note_count);
+        Self {
+            gauge_type,
+            rules,
+            value: rules.initial,
+            failed: false,
+            trace: Vec::new(),
+        }
+    }
+    pub fn for_chart(gauge_type: GaugeType, bms: &ImportedBMS, cbms: &CBMS) -> Self {
+        let note_count = cbms.playable_note_count();
+        Self::new(gauge_type, bms.total.unwrap_or_else(|| default_total(note_count)), note_count)
+    }
+    pub fn gauge_type(&self) -> GaugeType {
+        self.gauge_type
+    }
+    pub fn rules(&self) -> &GaugeRules {
+        &self.rules
+    }
+    pub fn value(&self) -> f64 {
+        self.value
+    }
+    pub fn trace(&self) -> &[(f64, f64)] {
+        &self.trace
+    }
+    pub fn is_failed(&self) -> bool {
+        self.failed
+    }
+    pub fn apply(&mut self, result: &JudgeResult) {
+        if self.failed { return; }
+        let mut change = self.rules.increments.get(result.judgement);
+        if change < 0.0 && self.value < self.rules.low_life_threshold {
+            change *= self.rules.low_life_factor;
+        }
+        self.value = (self.value + change).clamp(self.rules.minimum, 100.0);
+        if self.rules.clear_threshold.is_none() && self.value <= 0.0 {
+            self.value = 0.0;
+            self.failed = true;
+        }
+        self.trace.push((result.time, self.value));
+    }
+    pub fn apply_all(&mut self, results: &[JudgeResult]) {
+        for result in results {
+            self.apply(result);
+        }
+    }
+    //Whether the play cleared, meaningful once every judgement was applied
+    pub fn is_cleared(&self) -> bool {
+        match self.rules.clear_threshold {
+            Some(threshold) => self.value >= threshold,
+            None => !self.failed,
+        }
+    }
+    pub fn clear_lamp(&self) -> ClearLamp {
+        if self.is_cleared() { self.gauge_type.clear_lamp() } else { ClearLamp::Failed }
+    }
+}
+
+pub fn simulate_gauge(gauge_type: GaugeType, total: f64, note_count: usize, results: &[JudgeResult]) -> Gauge {
+    let mut gauge = Gauge::new(gauge_type, total, note_count);
+    gauge.apply_all(results);
+    gauge
+}
+
+#[cfg(test)]
+fn test_results(judgements: &[Judgement]) -> Vec<JudgeResult> {
+    judgements.iter()
+        .enumerate()
+        .map(|(i, judgement)| JudgeResult { time: i as f64, lane: 0, note: Some(i), offset: None, judgement: *judgement })
+        .collect()
+}
+
+#[cfg(test)]
+#[test]
+fn test_normal_gauge() {
+    //Total of 300 over 5 notes gives 60% per PGREAT
+    let results = test_results(&[Judgement::PGreat, Judgement::Poor, Judgement::Good]);
+    let gauge = simulate_gauge(GaugeType::Normal, 300.0, 5, &results);
+    assert_eq!(gauge.trace(), &[(0.0, 80.0), (1.0, 74.0), (2.0, 100.0)]);
+    assert_eq!(gauge.clear_lamp(), ClearLamp::Clear);
+    let gauge = simulate_gauge(GaugeType::Normal, 300.0, 5, &results[1 .. 2]);
+    assert_eq!((gauge.value(), gauge.is_cleared()), (14.0, false));
+}
+
+#[cfg(test)]
+#[test]
+fn test_hard_gauge_low_life_and_fail() {
+    let mut gauge = Gauge::new(GaugeType::Hard, 300.0, 100);
+    gauge.apply_all(&test_results(&[Judgement::Poor; 8]));
+    //Eight POORs bring it to 20%, below 30% damage is reduced
+    assert!((gauge.value() - 20.0).abs() < 1e-9);
+    gauge.apply_all(&test_results(&[Judgement::Poor]));
+    assert!((gauge.value() - 14.0).abs() < 1e-9);
+    gauge.apply_all(&test_results(&[Judgement::Poor; 3]));
+    assert!(gauge.is_failed());
+    assert_eq!(gauge.clear_lamp(), ClearLamp::Failed);
+    assert_eq!(default_total(500), 256.0);
+}
@@ -0,0 +1,83 @@
+use md5::{Digest, Md5};
+use sha2::Sha256;
+
+//LR2 keys charts by the MD5 of the file, beatoraja by the SHA-256
+pub fn md5(data: &[u8]) -> [u8; 16] {
+    Md5::digest(data).into()
+}
+
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+//All three hashes of a file that's read a line at a time
+#[derive(Default)]
+pub struct ChartHasher {
+    md5: Md5,
+    sha256: Sha256,
+    normalized: Sha256,
+}
+
+impl ChartHasher {
+    //A line as it is in the file, line ending included
+    pub fn update_line(&mut self, line: &[u8]) {
+        self.md5.update(line);
+        self.sha256.update(line);
+        update_normalized(&mut self.normalized, line);
+    }
+    //MD5, SHA-256 and normalized hash
+    pub fn finish(self) -> ([u8; 16], [u8; 32], [u8; 32]) {
+        (self.md5.finalize().into(), self.sha256.finalize().into(), self.normalized.finalize().into())
+    }
+}
+
+//Lowercase, the way score databases and difficulty tables store hashes
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    let hex = hex.trim();
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() { return None; }
+    (0 .. hex.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i .. i + 2], 16).ok())
+        .collect()
+}
+
+//SHA-256 of the chart with comments, blank lines, line endings and repeated
+//whitespace left out, so copies of a chart differing only in those hash the same
+pub fn normalized_hash(raw_bms: &str) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    for line in raw_bms.as_bytes().split(|&b| b == b'\n') {
+        update_normalized(&mut hasher, line);
+    }
+    hasher.finalize().into()
+}
+
+//Works on bytes, so Shift-JIS files hash the same way as UTF-8 ones
+fn update_normalized(hasher: &mut Sha256, line: &[u8]) {
+    let line = line.trim_ascii();
+    //Only lines starting with # mean anything, the rest are comments
+    if !line.starts_with(b"#") { return; }
+    let mut words = line.split(u8::is_ascii_whitespace).filter(|word| !word.is_empty());
+    let command = words.next().unwrap_or_default().to_ascii_uppercase();
+    hasher.update(&command);
+    for word in words {
+        hasher.update(b" ");
+        hasher.update(word);
+    }
+    hasher.update(b"\n");
+}
+
+#[cfg(test)]
+#[test]
+fn test_hashes() {
+    assert_eq!(to_hex(&md5(b"")), "d41d8cd98f00b204e9800998ecf8427e");
+    assert_eq!(to_hex(&sha256(b"abc")), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    assert_eq!(from_hex("00ff10"), Some(vec![0, 255, 16]));
+    assert_eq!(from_hex("0g"), None);
+    let a = normalized_hash("#TITLE  Song\r\n\r\n*comment\n#00111:0101\n");
+    let b = normalized_hash("  #title Song\n#00111:0101  \n// another comment");
+    assert_eq!(a, b);
+    assert_ne!(a, normalized_hash("#TITLE Song\n#00111:0100\n"));
+}
@@ -0,0 +1,306 @@
+use crate::cbms::{NoteKind, PlayableNote};
+use crate::compiler::ImportedBMS;
+
+use std::collections::VecDeque;
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum Judgement {
+    PGreat,
+    Great,
+    Good,
+    Bad,
+    Poor,
+    //Key pressed with no note to hit
+    EmptyPoor,
+}
+
+//How far from the note (in seconds) an input may land on each side
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct JudgeWindow {
+    pub early: f64,
+    pub late: f64,
+}
+
+impl JudgeWindow {
+    pub fn symmetric(width: f64) -> Self {
+        Self { early: width, late: width }
+    }
+    pub fn contains(&self, offset: f64) -> bool {
+        -self.early <= offset && offset <= self.late
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct JudgeWindows {
+    pub pgreat: JudgeWindow,
+    pub great: JudgeWindow,
+    pub good: JudgeWindow,
+    pub bad: JudgeWindow,
+    //Presses that hit no note are empty POORs, unless they're more than `early` before
+    //the next note of their lane. Only `early` is used.
+    pub empty_poor: JudgeWindow,
+}
+
+impl JudgeWindows {
+    //#RANK 0 is VERY HARD, 1 HARD, 2 NORMAL and 3 or more EASY
+    pub fn from_rank(rank: u32) -> Self {
+        let (pgreat, great, good) = match rank {
+            0 => (0.008, 0.024, 0.040),
+            1 => (0.015, 0.030, 0.060),
+            2 => (0.018, 0.040, 0.100),
+            _ => (0.021, 0.060, 0.120),
+        };
+        Self::from_widths(pgreat, great, good)
+    }
+    //#DEFEXRANK scales the NORMAL windows, 100 being NORMAL itself
+    pub fn from_defexrank(percent: f64) -> Self {
+        let scale = percent.max(0.0) / 100.0;
+        Self::from_widths(0.018 * scale, 0.040 * scale, 0.100 * scale)
+    }
+    pub fn for_chart(bms: &ImportedBMS) -> Self {
+        match bms.defexrank {
+            Some(percent) => Self::from_defexrank(percent),
+            None => Self::from_rank(bms.rank),
+        }
+    }
+    //BAD reaches further early than late, so mashing ahead of a note is caught by it
+    fn from_widths(pgreat: f64, great: f64, good: f64) -> Self {
+        Self {
+            pgreat: JudgeWindow::symmetric(pgreat),
+            great: JudgeWindow::symmetric(great),
+            good: JudgeWindow::symmetric(good),
+            bad: JudgeWindow { early: 0.280, late: 0.220 },
+            empty_poor: JudgeWindow { early: 1.0, late: 0.0 },
+        }
+    }
+    //Judgement for an input `offset` seconds from the note, None when out of the BAD window
+    pub fn judge(&self, offset: f64) -> Option<Judgement> {
+        if self.pgreat.contains(offset) {
+            Some(Judgement::PGreat)
+        } else if self.great.contains(offset) {
+            Some(Judgement::Great)
+        } else if self.good.contains(offset) {
+            Some(Judgement::Good)
+        } else if self.bad.contains(offset) {
+            Some(Judgement::Bad)
+        } else { None }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct KeyEvent {
+    pub time: f64,
+    pub lane: u32,
+    pub pressed: bool,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct JudgeResult {
+    //When the judgement happened
+    pub time: f64,
+    pub lane: u32,
+    //Index into the judged notes, None for empty POORs
+    pub note: Option<usize>,
+    //Input time minus note time, negative is early. None for misses.
+    pub offset: Option<f64>,
+    pub judgement: Judgement,
+}
+
+pub struct Judge {
+    windows: JudgeWindows,
+    notes: Vec<PlayableNote>,
+    //Normal notes and long note starts waiting to be hit, per lane
+    pending: Vec<VecDeque<usize>>,
+    //Index of the long note start each note ends, for long note ends
+    ln_ends: Vec<Option<usize>>,
+    //Long note end index currently held, per lane
+    held: Vec<Option<usize>>,
+    results: Vec<JudgeResult>,
+}
+
+impl Judge {
+    pub fn new(notes: Vec<PlayableNote>, windows: JudgeWindows) -> Self {
+        let lanes = notes.iter().map(|n| n.lane as usize + 1).max().unwrap_or(0);
+        let mut pending = vec![VecDeque::new(); lanes];
+        let mut ln_ends = vec![None; notes.len()];
+        let mut open_lns: Vec<Option<usize>> = vec![None; lanes];
+        for (idx, note) in notes.iter().enumerate() {
+            match note.kind {
+                NoteKind::Normal => pending[note.lane as usize].push_back(idx),
+                NoteKind::LongStart => {
+                    pending[note.lane as usize].push_back(idx);
+                    open_lns[note.lane as usize] = Some(idx);
+                },
+                NoteKind::LongEnd => if let Some(start) = open_lns[note.lane as usize].take() {
+                    ln_ends[start] = Some(idx);
+                },
+            }
+        }
+        Self {
+            windows,
+            notes,
+            pending,
+            ln_ends,
+            held: vec![None; lanes],
+            results: Vec::new(),
+        }
+    }
+    pub fn notes(&self) -> &[PlayableNote] {
+        &self.notes
+    }
+    pub fn windows(&self) -> &JudgeWindows {
+        &self.windows
+    }
+    pub fn results(&self) -> &[JudgeResult] {
+        &self.results
+    }
+    //Marks every note whose window has passed by `time` as missed. Returns the new results.
+    pub fn update(&mut self, time: f64) -> &[JudgeResult] {
+        let beg = self.results.len();
+        self.expire(time);
+        &self.results[beg ..]
+    }
+    fn expire(&mut self, time: f64) {
+        for lane in 0 .. self.pending.len() {
+            while let Some(&idx) = self.pending[lane].front() {
+                if self.notes[idx].time + self.windows.bad.late >= time { break; }
+                self.pending[lane].pop_front();
+                self.miss(idx, time);
+                if let Some(end) = self.ln_ends[idx] {
+                    self.miss(end, time);
+                }
+            }
+            //Holding a charge note for too long is a miss as well
+            if let Some(end) = self.held[lane] {
+                if self.notes[end].time + self.windows.bad.late < time {
+                    self.held[lane] = None;
+                    self.miss(end, time);
+                }
+            }
+        }
+    }
+    fn miss(&mut self, idx: usize, time: f64) {
+        self.results.push(JudgeResult {
+            time,
+            lane: self.notes[idx].lane,
+            note: Some(idx),
+            offset: None,
+            judgement: Judgement::Poor,
+        });
+    }
+    //Inputs have to come in chronological order. Returns the new results.
+    pub fn input(&mut self, event: KeyEvent) -> &[JudgeResult] {
+        let beg = self.results.len();
+        self.expire(event.time);
+        let lane = event.lane as usize;
+        if lane < self.pending.len() {
+            if event.pressed {
+                self.press(lane, event.time);
+            } else {
+                self.release(lane, event.time);
+            }
+        //Lanes without notes are like lanes whose notes have all been played
+        } else if event.pressed {
+            self.empty_poor(lane, event.time);
+        }
+        &self.results[beg ..]
+    }
+    fn press(&mut self, lane: usize, time: f64) {
+        let front = self.pending[lane].front().cloned();
+        let offset = front.map(|idx| time - self.notes[idx].time);
+        match (front, offset.and_then(|o| self.windows.judge(o))) {
+            (Some(idx), Some(judgement)) => {
+                self.pending[lane].pop_front();
+                self.held[lane] = self.ln_ends[idx];
+                self.results.push(JudgeResult { time, lane: lane as u32, note: Some(idx), offset, judgement });
+            },
+            (Some(_), None) if -offset.unwrap() > self.windows.empty_poor.early => (),
+            _ => self.empty_poor(lane, time),
+        }
+    }
+    fn empty_poor(&mut self, lane: usize, time: f64) {
+        self.results.push(JudgeResult { time, lane: lane as u32, note: None, offset: None, judgement: Judgement::EmptyPoor });
+    }
+    fn release(&mut self, lane: usize, time: f64) {
+        if let Some(end) = self.held[lane].take() {
+            let offset = time - self.notes[end].time;
+            //Letting go before the release window breaks the long note
+            let judgement = self.windows.judge(offset).unwrap_or(Judgement::Poor);
+            self.results.push(JudgeResult { time, lane: lane as u32, note: Some(end), offset: Some(offset), judgement });
+        }
+    }
+    //Misses everything that's left, call once the input is over
+    pub fn finish(&mut self) -> &[JudgeResult] {
+        self.update(f64::INFINITY)
+    }
+}
+
+//Judges a whole recorded input stream at once
+pub fn judge_inputs(notes: Vec<PlayableNote>, windows: JudgeWindows, inputs: &[KeyEvent]) -> Vec<JudgeResult> {
+    let mut judge = Judge::new(notes, windows);
+    for event in inputs {
+        judge.input(*event);
+    }
+    judge.finish();
+    judge.results
+}
+
+#[cfg(test)]
+fn test_notes(raw_bms: &str) -> Vec<PlayableNote> {
+    let bms = crate::compiler::import_bms(raw_bms).unwrap();
+    bms.eval_and_compile().playable_notes(&bms.timing, &bms.stops)
+}
+
+#[cfg(test)]
+#[test]
+fn test_judge_windows() {
+    let windows = JudgeWindows::from_rank(2);
+    assert_eq!(windows.judge(-0.010), Some(Judgement::PGreat));
+    assert_eq!(windows.judge(0.030), Some(Judgement::Great));
+    assert_eq!(windows.judge(-0.090), Some(Judgement::Good));
+    assert_eq!(windows.judge(0.150), Some(Judgement::Bad));
+    assert_eq!(windows.judge(-0.250), Some(Judgement::Bad));
+    assert_eq!(windows.judge(0.250), None);
+    assert_eq!(JudgeWindows::from_defexrank(50.0).judge(0.015), Some(Judgement::Great));
+}
+
+#[cfg(test)]
+#[test]
+fn test_judge_inputs() {
+    //Notes on lane 0 at 0.0s and 0.5s, on lane 1 at 0.25s
+    let notes = test_notes("#BPM 240\n#00011:0101\n#00012:00010000");
+    let press = |time, lane| KeyEvent { time, lane, pressed: true };
+    let inputs = [press(0.01, 0), press(0.1, 0), press(0.28, 1), press(0.3, 1), press(0.3, 5)];
+    let judgements: Vec<(Option<usize>, Judgement)> = judge_inputs(notes, JudgeWindows::from_rank(2), &inputs)
+        .iter()
+        .map(|r| (r.note, r.judgement))
+        .collect();
+    assert_eq!(judgements, vec![
+        (Some(0), Judgement::PGreat),
+        (None, Judgement::EmptyPoor),
+        (Some(1), Judgement::Great),
+        //Lane 1 has no notes left and lane 5 never had any
+        (None, Judgement::EmptyPoor),
+        (None, Judgement::EmptyPoor),
+        (Some(2), Judgement::Poor),
+    ]);
+}
+
+#[cfg(test)]
+#[test]
+fn test_judge_long_note_release() {
+    //Long notes on lane 0 from 0.0s to 0.5s and from 1.0s to 1.5s
+    let notes = test_notes("#BPM 240\n#00051:0101\n#00151:0101");
+    let key = |time, pressed| KeyEvent { time, lane: 0, pressed };
+    let inputs = [key(0.0, true), key(0.51, false), key(1.0, true), key(1.2, false)];
+    let judgements: Vec<(usize, Judgement)> = judge_inputs(notes, JudgeWindows::from_rank(2), &inputs)
+        .iter()
+        .map(|r| (r.note.unwrap(), r.judgement))
+        .collect();
+    assert_eq!(judgements, vec![
+        (0, Judgement::PGreat),
+        (1, Judgement::PGreat),
+        (2, Judgement::PGreat),
+        (3, Judgement::Poor),
+    ]);
+}
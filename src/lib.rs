@@ -6,9 +6,26 @@ extern crate lazy_static;
 
 pub mod util;
 pub mod bms;
+pub mod bmson;
 pub mod cbms;
 pub mod compiler;
+pub mod dialect;
 pub mod cbms_printer;
+pub mod audio;
+pub mod library;
+pub mod judge;
+pub mod score;
+pub mod difficulty;
+pub mod dtx;
+pub mod gauge;
+pub mod hash;
+pub mod midi;
+pub mod osu;
+pub mod patterns;
+pub mod shuffle;
+pub mod stepmania;
+pub mod table;
+pub mod replay;
 #[cfg(test)]
 mod tests;
 
@@ -0,0 +1,463 @@
+use crate::bmson::{BmsonImportError, import_bmson_bytes};
+use crate::compiler::{BMSImportError, BMSReader, ImportedBMS};
+use crate::dialect::Dialect;
+use crate::hash;
+use crate::shuffle::PlayStyle;
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+pub const CHART_EXTENSIONS: &[&str] = &["bms", "bme", "bml", "pms", "bmson"];
+const LIBRARY_HEADER: &str = "mbms-library 1";
+
+#[derive(Copy, Clone, Debug)]
+pub enum LibraryError {
+    CouldntOpenFile,
+    ErrorReadingFile,
+    ErrorWritingFile,
+    InvalidFormat,
+    Import(BMSImportError),
+    ImportBmson(BmsonImportError),
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum KeyMode {
+    Keys5,
+    Keys7,
+    //Pop'n Music charts
+    Keys9,
+    Keys10,
+    Keys14,
+}
+
+impl KeyMode {
+    //PMS charts use both sides for their 9 buttons, so they go by extension
+    pub fn detect(path: &Path, style: PlayStyle) -> Self {
+        if extension(path).as_deref() == Some("pms") { return KeyMode::Keys9; }
+        match style {
+            PlayStyle::Single5 => KeyMode::Keys5,
+            PlayStyle::Single7 => KeyMode::Keys7,
+            PlayStyle::Double5 => KeyMode::Keys10,
+            PlayStyle::Double7 => KeyMode::Keys14,
+        }
+    }
+    //The mode_hint of bmson charts, None for modes without a key mode of their own
+    pub fn from_mode_hint(mode_hint: &str) -> Option<Self> {
+        match mode_hint {
+            "beat-5k" => Some(KeyMode::Keys5),
+            "beat-7k" => Some(KeyMode::Keys7),
+            "beat-10k" => Some(KeyMode::Keys10),
+            "beat-14k" => Some(KeyMode::Keys14),
+            "popn-9k" => Some(KeyMode::Keys9),
+            _ => None,
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            KeyMode::Keys5 => "5K",
+            KeyMode::Keys7 => "7K",
+            KeyMode::Keys9 => "9K",
+            KeyMode::Keys10 => "10K",
+            KeyMode::Keys14 => "14K",
+        }
+    }
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "5K" => Some(KeyMode::Keys5),
+            "7K" => Some(KeyMode::Keys7),
+            "9K" => Some(KeyMode::Keys9),
+            "10K" => Some(KeyMode::Keys10),
+            "14K" => Some(KeyMode::Keys14),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct ChartEntry {
+    pub path: PathBuf,
+    //Nanoseconds since the epoch, used to tell which charts changed since the last scan
+    pub modified: u64,
+    pub title: String,
+    pub md5: [u8; 16],
+    pub sha256: [u8; 32],
+    pub key_mode: KeyMode,
+    pub playlevel: Option<u32>,
+    pub note_count: usize,
+    //Seconds
+    pub length: f64,
+    pub bpm: f32,
+}
+
+impl ChartEntry {
+    //Charts of one song share a folder
+    pub fn song_folder(&self) -> &Path {
+        self.path.parent().unwrap_or_else(|| Path::new(""))
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ScanReport {
+    pub added: Vec<PathBuf>,
+    pub updated: Vec<PathBuf>,
+    pub removed: Vec<PathBuf>,
+    pub unchanged: usize,
+    pub failed: Vec<(PathBuf, LibraryError)>,
+}
+
+fn extension(path: &Path) -> Option<String> {
+    path.extension().map(|e| e.to_string_lossy().to_ascii_lowercase())
+}
+
+pub fn is_chart_file(path: &Path) -> bool {
+    extension(path).is_some_and(|e| CHART_EXTENSIONS.contains(&e.as_str()))
+}
+
+fn modified_time(path: &Path) -> Result<u64, LibraryError> {
+    let modified = std::fs::metadata(path)
+        .and_then(|m| m.modified())
+        .map_err(|_| LibraryError::CouldntOpenFile)?;
+    Ok(modified.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64))
+}
+
+//Every chart file under `root`, sorted so scans always go in the same order
+pub fn find_chart_files(root: &Path) -> Vec<PathBuf> {
+    let mut found = Vec::new();
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else if is_chart_file(&path) {
+                found.push(path);
+            }
+        }
+    }
+    found.sort();
+    found
+}
+
+fn chart_reader(path: &Path) -> Result<BMSReader<BufReader<File>>, LibraryError> {
+    let file = File::open(path).map_err(|_| LibraryError::CouldntOpenFile)?;
+    let dialect = if extension(path).as_deref() == Some("pms") { Dialect::pms() } else { Dialect::bms() };
+    Ok(BMSReader::with_dialect(BufReader::new(file), dialect))
+}
+
+//Title, level, BPM and hashes of a chart without compiling it. BMS files are parsed
+//only up to their first object, bmson files are read whole.
+pub fn scan_chart_header(path: &Path) -> Result<ImportedBMS, LibraryError> {
+    if extension(path).as_deref() == Some("bmson") {
+        let raw = std::fs::read(path).map_err(|_| LibraryError::ErrorReadingFile)?;
+        return import_bmson_bytes(&raw).map(|chart| chart.bms).map_err(LibraryError::ImportBmson);
+    }
+    chart_reader(path)?.read_header().map_err(LibraryError::Import)
+}
+
+pub fn scan_chart(path: &Path) -> Result<ChartEntry, LibraryError> {
+    let modified = modified_time(path)?;
+    let (bms, hinted_mode) = if extension(path).as_deref() == Some("bmson") {
+        let raw = std::fs::read(path).map_err(|_| LibraryError::ErrorReadingFile)?;
+        let chart = import_bmson_bytes(&raw).map_err(LibraryError::ImportBmson)?;
+        (chart.bms, KeyMode::from_mode_hint(&chart.mode_hint))
+    } else {
+        (chart_reader(path)?.finish().map_err(LibraryError::Import)?, None)
+    };
+    let cbms = bms.eval_and_compile();
+    let notes = cbms.playable_notes(&bms.timing, &bms.stops);
+    let stats = cbms.statistics(&bms.timing, &bms.stops);
+    Ok(ChartEntry {
+        path: path.to_path_buf(),
+        modified,
+        title: bms.title,
+        md5: bms.md5,
+        sha256: bms.sha256,
+        key_mode: hinted_mode.unwrap_or_else(|| KeyMode::detect(path, PlayStyle::detect(&notes))),
+        playlevel: bms.playlevel,
+        note_count: stats.note_count,
+        length: stats.length,
+        bpm: bms.bpm,
+    })
+}
+
+//Tabs, newlines and backslashes are escaped so every entry takes one line
+fn escape(field: &str) -> String {
+    field.replace('\\', "\\\\").replace('\t', "\\t").replace('\n', "\\n").replace('\r', "\\r")
+}
+
+//Bytes of paths that aren't UTF-8 are written as \xNN
+fn unescape_bytes(field: &str) -> Vec<u8> {
+    let mut out = Vec::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some('t') => out.push(b'\t'),
+            Some('n') => out.push(b'\n'),
+            Some('r') => out.push(b'\r'),
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                out.extend(u8::from_str_radix(&hex, 16).ok());
+            },
+            Some(c) => out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+            None => (),
+        }
+    }
+    out
+}
+
+fn unescape(field: &str) -> String {
+    String::from_utf8_lossy(&unescape_bytes(field)).into_owned()
+}
+
+//Paths are kept as they are, even when they aren't valid Unicode
+#[cfg(unix)]
+fn escape_path(path: &Path) -> Option<String> {
+    use std::os::unix::ffi::OsStrExt;
+    let mut out = String::new();
+    for chunk in path.as_os_str().as_bytes().utf8_chunks() {
+        out.push_str(&escape(chunk.valid()));
+        for byte in chunk.invalid() {
+            out.push_str(&format!("\\x{:02x}", byte));
+        }
+    }
+    Some(out)
+}
+
+#[cfg(unix)]
+fn unescape_path(field: &str) -> Option<PathBuf> {
+    use std::os::unix::ffi::OsStringExt;
+    Some(PathBuf::from(std::ffi::OsString::from_vec(unescape_bytes(field))))
+}
+
+//Elsewhere paths that aren't valid Unicode can't be stored
+#[cfg(not(unix))]
+fn escape_path(path: &Path) -> Option<String> {
+    path.to_str().map(escape)
+}
+
+#[cfg(not(unix))]
+fn unescape_path(field: &str) -> Option<PathBuf> {
+    String::from_utf8(unescape_bytes(field)).ok().map(PathBuf::from)
+}
+
+fn format_entry(entry: &ChartEntry) -> Option<String> {
+    Some([
+        escape_path(&entry.path)?,
+        entry.modified.to_string(),
+        escape(&entry.title),
+        hash::to_hex(&entry.md5),
+        hash::to_hex(&entry.sha256),
+        entry.key_mode.name().to_string(),
+        entry.playlevel.map_or("-".to_string(), |l| l.to_string()),
+        entry.note_count.to_string(),
+        entry.length.to_string(),
+        entry.bpm.to_string(),
+    ].join("\t"))
+}
+
+fn parse_entry(line: &str) -> Option<ChartEntry> {
+    let fields: Vec<&str> = line.split('\t').collect();
+    if fields.len() != 10 { return None; }
+    let mut md5 = [0; 16];
+    md5.copy_from_slice(&hash::from_hex(fields[3]).filter(|h| h.len() == 16)?);
+    let mut sha256 = [0; 32];
+    sha256.copy_from_slice(&hash::from_hex(fields[4]).filter(|h| h.len() == 32)?);
+    Some(ChartEntry {
+        path: unescape_path(fields[0])?,
+        modified: fields[1].parse().ok()?,
+        title: unescape(fields[2]),
+        md5,
+        sha256,
+        key_mode: KeyMode::from_name(fields[5])?,
+        playlevel: if fields[6] == "-" { None } else { Some(fields[6].parse().ok()?) },
+        note_count: fields[7].parse().ok()?,
+        length: fields[8].parse().ok()?,
+        bpm: fields[9].parse().ok()?,
+    })
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Library {
+    entries: BTreeMap<PathBuf, ChartEntry>,
+}
+
+impl Library {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn entries(&self) -> impl Iterator<Item = &ChartEntry> {
+        self.entries.values()
+    }
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+    pub fn get(&self, path: &Path) -> Option<&ChartEntry> {
+        self.entries.get(path)
+    }
+    pub fn find_md5(&self, md5: &[u8; 16]) -> Option<&ChartEntry> {
+        self.entries().find(|e| &e.md5 == md5)
+    }
+    pub fn find_sha256(&self, sha256: &[u8; 32]) -> Option<&ChartEntry> {
+        self.entries().find(|e| &e.sha256 == sha256)
+    }
+    //Charts grouped by song folder
+    pub fn songs(&self) -> BTreeMap<&Path, Vec<&ChartEntry>> {
+        let mut songs: BTreeMap<&Path, Vec<&ChartEntry>> = BTreeMap::new();
+        for entry in self.entries() {
+            songs.entry(entry.song_folder()).or_default().push(entry);
+        }
+        songs
+    }
+    //Brings the part of the library under `root` up to date. Only charts whose
+    //modification time changed since the last scan are looked at again, and of those
+    //only the ones whose hashes changed are imported again.
+    pub fn scan(&mut self, root: &Path) -> ScanReport {
+        let mut report = ScanReport::default();
+        let found = find_chart_files(root);
+        let gone: Vec<PathBuf> = self.entries.keys()
+            .filter(|path| path.starts_with(root) && found.binary_search(path).is_err())
+            .cloned()
+            .collect();
+        for path in gone {
+            self.entries.remove(&path);
+            report.removed.push(path);
+        }
+        for path in found {
+            let known = self.entries.get(&path).map(|e| e.modified);
+            let modified = modified_time(&path).ok();
+            if known.is_some() && known == modified {
+                report.unchanged += 1;
+                continue;
+            }
+            if let (Some(entry), Some(modified)) = (self.entries.get_mut(&path), modified) {
+                let same = scan_chart_header(&path).is_ok_and(|header| header.md5 == entry.md5 && header.sha256 == entry.sha256);
+                if same {
+                    entry.modified = modified;
+                    report.unchanged += 1;
+                    continue;
+                }
+            }
+            match scan_chart(&path) {
+                Ok(entry) => {
+                    self.entries.insert(path.clone(), entry);
+                    if known.is_some() { report.updated.push(path) } else { report.added.push(path) }
+                },
+                Err(e) => {
+                    if self.entries.remove(&path).is_some() {
+                        report.removed.push(path.clone());
+                    }
+                    report.failed.push((path, e));
+                },
+            }
+        }
+        report
+    }
+    pub fn save(&self, path: &Path) -> Result<(), LibraryError> {
+        let mut out = String::from(LIBRARY_HEADER);
+        out.push('\n');
+        for entry in self.entries() {
+            out.push_str(&format_entry(entry).ok_or(LibraryError::ErrorWritingFile)?);
+            out.push('\n');
+        }
+        std::fs::write(path, out).map_err(|_| LibraryError::ErrorWritingFile)
+    }
+    pub fn load(path: &Path) -> Result<Self, LibraryError> {
+        let data = std::fs::read_to_string(path).map_err(|_| LibraryError::CouldntOpenFile)?;
+        let mut lines = data.lines();
+        if lines.next() != Some(LIBRARY_HEADER) { return Err(LibraryError::InvalidFormat); }
+        let mut library = Self::new();
+        for line in lines.filter(|l| !l.is_empty()) {
+            let entry = parse_entry(line).ok_or(LibraryError::InvalidFormat)?;
+            library.entries.insert(entry.path.clone(), entry);
+        }
+        Ok(library)
+    }
+    //Loads the database at `db_path` if there is one, scans `root` and saves the result
+    pub fn update(db_path: &Path, root: &Path) -> Result<(Self, ScanReport), LibraryError> {
+        let mut library = if db_path.exists() { Self::load(db_path)? } else { Self::new() };
+        let report = library.scan(root);
+        library.save(db_path)?;
+        Ok((library, report))
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_library_scan_and_update() {
+    use std::time::{Duration, SystemTime};
+    let root = std::env::temp_dir().join(format!("mbms-library-test-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    std::fs::create_dir_all(root.join("song a")).unwrap();
+    std::fs::create_dir_all(root.join("song b")).unwrap();
+    std::fs::write(root.join("song a/normal.bms"), "#TITLE Song A\t[N]\n#PLAYLEVEL 3\n#BPM 240\n#00111:01010101\n").unwrap();
+    std::fs::write(root.join("song a/another.bme"), "#TITLE Song A\n#BPM 120\n#00119:01\n#00121:01\n").unwrap();
+    std::fs::write(root.join("song b/chart.bmson"), r#"{"info": {"title": "Song B", "init_bpm": 150, "mode_hint": "popn-9k"}}"#).unwrap();
+    std::fs::write(root.join("song b/broken.bmson"), "{}").unwrap();
+    std::fs::write(root.join("song b/readme.txt"), "not a chart").unwrap();
+    let db = root.join("library.db");
+
+    let (library, report) = Library::update(&db, &root).unwrap();
+    assert_eq!((report.added.len(), report.failed.len()), (3, 1));
+    let normal = library.get(&root.join("song a/normal.bms")).unwrap();
+    assert_eq!((normal.title.as_str(), normal.key_mode, normal.playlevel), ("Song A\t[N]", KeyMode::Keys5, Some(3)));
+    assert_eq!((normal.note_count, normal.length), (4, 1.75));
+    assert_eq!(library.get(&root.join("song a/another.bme")).unwrap().key_mode, KeyMode::Keys14);
+    assert_eq!(library.get(&root.join("song b/chart.bmson")).unwrap().key_mode, KeyMode::Keys9);
+    assert_eq!(library.songs().len(), 2);
+    assert_eq!(library.find_md5(&normal.md5).map(|e| &e.path), Some(&normal.path));
+
+    //Nothing changed, then one chart is touched without changing
+    let (_, report) = Library::update(&db, &root).unwrap();
+    assert_eq!((report.unchanged, report.added.len()), (3, 0));
+    let touched = SystemTime::now() + Duration::from_secs(5);
+    std::fs::File::options().write(true).open(root.join("song a/another.bme")).unwrap().set_modified(touched).unwrap();
+    let (library, report) = Library::update(&db, &root).unwrap();
+    assert_eq!((report.unchanged, report.updated.len()), (3, 0));
+    assert_eq!(library.get(&root.join("song a/another.bme")).unwrap().modified, modified_time(&root.join("song a/another.bme")).unwrap());
+    //Then one chart is edited and another one deleted
+    std::fs::write(root.join("song a/normal.bms"), "#TITLE Song A\n#BPM 240\n#00111:01\n").unwrap();
+    let file = std::fs::File::options().write(true).open(root.join("song a/normal.bms")).unwrap();
+    file.set_modified(SystemTime::now() + Duration::from_secs(10)).unwrap();
+    std::fs::remove_file(root.join("song a/another.bme")).unwrap();
+    let (library, report) = Library::update(&db, &root).unwrap();
+    assert_eq!((report.updated.len(), report.removed.len(), report.unchanged), (1, 1, 1));
+    assert_eq!(library.len(), 2);
+    assert_eq!(Library::load(&db).unwrap().get(&root.join("song a/normal.bms")).unwrap().note_count, 1);
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[cfg(all(test, unix))]
+#[test]
+fn test_library_non_utf8_paths() {
+    use std::os::unix::ffi::OsStringExt;
+    let path = PathBuf::from(std::ffi::OsString::from_vec(b"songs/\x82\xa0\tx\\/chart.bms".to_vec()));
+    let entry = ChartEntry {
+        path: path.clone(),
+        modified: 1,
+        title: "Title".to_string(),
+        md5: [1; 16],
+        sha256: [2; 32],
+        key_mode: KeyMode::Keys7,
+        playlevel: None,
+        note_count: 3,
+        length: 1.5,
+        bpm: 120.0,
+    };
+    let line = format_entry(&entry).unwrap();
+    assert!(line.starts_with("songs/\\x82\\xa0\\tx\\\\/chart.bms\t"));
+    assert_eq!(parse_entry(&line), Some(entry));
+}
@@ -1,19 +1,10 @@
-#[macro_use]
-extern crate lazy_static;
-
-mod util;
-mod bms;
-mod cbms;
-mod compiler;
-mod cbms_printer;
-
-
-use self::compiler::*;
+use mbms::{bms, cbms, cbms_printer};
+use mbms::compiler::*;
 use std::io;
 use std::io::prelude::*;
 use std::str::FromStr;
 use std::fmt::Display;
-use self::util::GenericError;
+use mbms::util::GenericError;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args: Vec<String> = std::env::args().collect();
@@ -0,0 +1,661 @@
+use crate::bms::{BMSTime, BMSTimings, BMSStops};
+use crate::cbms::{BGM_CHANNEL, ChannelKind, LANES_PER_SIDE, NoteKind};
+use crate::compiler::{ChartBuilder, ImportedBMS};
+
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{Read, Write};
+
+pub const TICKS_PER_QUARTER: u16 = 480;
+const VELOCITY: u8 = 100;
+//Notes that aren't long notes, and BGM, sound for at most a sixteenth
+const SHORT_NOTE_TICKS: u64 = TICKS_PER_QUARTER as u64 / 4;
+//Imported time signatures go down to 64th notes
+const MAX_DENOMINATOR_POWER: u8 = 6;
+//BMS measure numbers have three digits
+const MAX_MEASURE: usize = 999;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum MidiError {
+    CouldntOpenFile,
+    ErrorReadingFile,
+    ErrorWritingFile,
+    InvalidFormat,
+    //Format 2 files and SMPTE timed files
+    UnsupportedFormat,
+}
+
+//What notes picked out by a rule become
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum MidiTarget {
+    Lane(u32),
+    Bgm,
+}
+
+//Which notes of the file go where. Fields left as None match anything.
+#[derive(Clone, PartialEq, Debug)]
+pub struct MidiRule {
+    pub track: Option<usize>,
+    pub track_name: Option<String>,
+    pub channel: Option<u8>,
+    //Lowest and highest note number
+    pub notes: Option<(u8, u8)>,
+    pub target: MidiTarget,
+    //None takes the keysound from the channel and note number, see `keysound_to_note`
+    pub keysound: Option<u32>,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct MidiMapping {
+    //The first rule matching a note decides where it goes, notes no rule matches are left out
+    pub rules: Vec<MidiRule>,
+    //Lane notes held longer than this many quarter notes become long notes, None for no long notes
+    pub long_note_length: Option<f64>,
+}
+
+//Keysounds are split into a channel and a note number, keysound = channel * 128 + note.
+//Every id up to ZZ fits in the 16 MIDI channels.
+pub fn keysound_to_note(keysound: u32) -> (u8, u8) {
+    (((keysound / 128) % 16) as u8, (keysound % 128) as u8)
+}
+
+pub fn note_to_keysound(channel: u8, note: u8) -> u32 {
+    channel as u32 * 128 + note as u32
+}
+
+//Lane names the way BMS players call the keys
+pub fn lane_name(lane: u32) -> String {
+    let side = lane / LANES_PER_SIDE + 1;
+    match lane % LANES_PER_SIDE {
+        key @ 0 ..= 4 => format!("{}P key {}", side, key + 1),
+        5 => format!("{}P scratch", side),
+        6 => format!("{}P free zone", side),
+        key => format!("{}P key {}", side, key - 1),
+    }
+}
+
+impl MidiRule {
+    pub fn new(target: MidiTarget) -> Self {
+        Self { track: None, track_name: None, channel: None, notes: None, target, keysound: None }
+    }
+    fn matches(&self, track: usize, track_name: &str, note: &MidiNote) -> bool {
+        self.track.is_none_or(|t| t == track)
+            && self.track_name.as_deref().is_none_or(|name| name == track_name)
+            && self.channel.is_none_or(|c| c == note.channel)
+            && self.notes.is_none_or(|(low, high)| low <= note.note && note.note <= high)
+    }
+}
+
+impl MidiMapping {
+    //Reads files written by `export_midi` back: tracks named after a lane go on it and
+    //the BGM track to BGM. Long notes shorter than a sixteenth come back as normal notes.
+    pub fn by_track_names() -> Self {
+        let mut rules: Vec<MidiRule> = (0 .. 2 * LANES_PER_SIDE)
+            .map(|lane| MidiRule { track_name: Some(lane_name(lane)), ..MidiRule::new(MidiTarget::Lane(lane)) })
+            .collect();
+        rules.push(MidiRule { track_name: Some("BGM".to_string()), ..MidiRule::new(MidiTarget::Bgm) });
+        Self {
+            rules,
+            long_note_length: Some(SHORT_NOTE_TICKS as f64 / TICKS_PER_QUARTER as f64),
+        }
+    }
+}
+
+//Chart positions in quarter notes from the start. MIDI can't stop, so every stop
+//becomes as many quarter notes as it lasts at its tempo, with nothing happening in them.
+struct TickMap<'t> {
+    timings: &'t BMSTimings,
+    stops: &'t BMSStops,
+}
+
+impl TickMap<'_> {
+    fn timing_at(&self, pos: BMSTime) -> (f32, f64) {
+        self.timings.iter()
+            .take_while(|(at, _, _)| *at <= pos)
+            .last()
+            .map_or((120.0, 4.0), |&(_, bpm, beats)| (bpm, beats))
+    }
+    fn quarters(&self, pos: BMSTime) -> f64 {
+        let mut quarters = 0.0;
+        for (idx, &(at, _, beats)) in self.timings.iter().enumerate() {
+            if at >= pos { break; }
+            let end = self.timings.get(idx + 1).map_or(pos, |next| if next.0 < pos { next.0 } else { pos });
+            quarters += f64::from(end - at) * beats;
+        }
+        let stopped: f64 = self.stops.iter()
+            .take_while(|(at, _)| *at < pos)
+            .map(|&(at, length)| length * self.timing_at(at).0 as f64 / 60.0)
+            .sum();
+        quarters + stopped
+    }
+    fn tick(&self, pos: BMSTime) -> u64 {
+        (self.quarters(pos) * TICKS_PER_QUARTER as f64).round() as u64
+    }
+}
+
+//Numerator and power of two denominator of a measure `beats` quarter notes long.
+//Lengths no power of two up to 64 divides evenly are rounded to sixteenths.
+fn time_signature(beats: f64) -> (u8, u8) {
+    let exact = (2 ..= 6).find_map(|power| {
+        let numerator = beats * (1u32 << power) as f64 / 4.0;
+        let whole = (numerator - numerator.round()).abs() < 1e-6 && (1.0 ..= 255.0).contains(&numerator);
+        Some((numerator.round() as u8, power)).filter(|_| whole)
+    });
+    exact.unwrap_or(((beats * 4.0).round().clamp(1.0, 255.0) as u8, 4))
+}
+
+fn write_vlq(bytes: &mut Vec<u8>, mut value: u64) {
+    let mut groups = vec![(value & 0x7F) as u8];
+    value >>= 7;
+    while value > 0 {
+        groups.push((value & 0x7F) as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.extend(groups.iter().rev());
+}
+
+fn meta_event(kind: u8, data: &[u8]) -> Vec<u8> {
+    let mut event = vec![0xFF, kind];
+    write_vlq(&mut event, data.len() as u64);
+    event.extend_from_slice(data);
+    event
+}
+
+//Events as (tick, order, bytes). At the same tick note offs (order 0) go before note ons (order 1),
+//so a note ending right as the next one starts doesn't cut that one short.
+type TrackEvents = Vec<(u64, u8, Vec<u8>)>;
+
+fn track_chunk(mut events: TrackEvents) -> Vec<u8> {
+    events.sort_by_key(|(tick, order, _)| (*tick, *order));
+    let mut data = Vec::new();
+    let mut last_tick = 0;
+    for (tick, _, event) in events {
+        write_vlq(&mut data, tick - last_tick);
+        data.extend(event);
+        last_tick = tick;
+    }
+    write_vlq(&mut data, 0);
+    data.extend(meta_event(0x2F, &[]));
+    let mut chunk = b"MTrk".to_vec();
+    chunk.extend(&(data.len() as u32).to_be_bytes());
+    chunk.extend(data);
+    chunk
+}
+
+fn push_note(events: &mut TrackEvents, start: u64, end: u64, keysound: u32) {
+    let (channel, note) = keysound_to_note(keysound);
+    events.push((start, 1, vec![0x90 | channel, note, VELOCITY]));
+    events.push((end.max(start + 1), 0, vec![0x80 | channel, note, 0]));
+}
+
+//Short notes last until the next object in the same group or SHORT_NOTE_TICKS, whichever is first
+fn short_note_ends(ticks: &[(u64, u32)]) -> Vec<u64> {
+    ticks.iter().enumerate()
+        .map(|(idx, &(tick, group))| {
+            let next = ticks[idx + 1 ..].iter().find(|&&(next, next_group)| next_group == group && next > tick);
+            next.map_or(tick + SHORT_NOTE_TICKS, |&(next, _)| next.min(tick + SHORT_NOTE_TICKS))
+        })
+        .collect()
+}
+
+//Writes the chart as a format 1 Standard MIDI File: a conductor track with the tempo map,
+//time signatures and the #WAV names as text events, a BGM track and a track per lane in use.
+//Keysounds are encoded in the channel and note number, see `keysound_to_note`.
+pub fn export_midi(bms: &ImportedBMS) -> Vec<u8> {
+    let ticks = TickMap { timings: &bms.timing, stops: &bms.stops };
+    let cbms = bms.eval_and_compile();
+    let mut conductor: TrackEvents = vec![(0, 0, meta_event(0x03, bms.title.as_bytes()))];
+    let mut last_signature = None;
+    for &(pos, bpm, beats) in &bms.timing {
+        let tick = ticks.tick(pos);
+        let tempo = ((60_000_000.0 / bpm as f64).round() as u32).clamp(1, 0xFF_FFFF);
+        conductor.push((tick, 0, meta_event(0x51, &tempo.to_be_bytes()[1 ..])));
+        let signature = time_signature(beats);
+        if last_signature != Some(signature) {
+            conductor.push((tick, 0, meta_event(0x58, &[signature.0, signature.1, 24, 8])));
+            last_signature = Some(signature);
+        }
+    }
+    for (idx, path) in bms.resource_table.iter().enumerate().filter(|(_, path)| !path.is_empty()) {
+        conductor.push((0, 0, meta_event(0x01, format!("WAV{} {}", idx, path).as_bytes())));
+    }
+    let mut tracks = vec![track_chunk(conductor)];
+    let bgm: Vec<(u64, u32)> = cbms.timed_commands(&bms.timing, &bms.stops).iter()
+        .filter(|tc| matches!(tc.command.kind(), ChannelKind::Bgm))
+        .map(|tc| (ticks.tick(tc.bms_time), tc.command.value))
+        .collect();
+    let mut bgm_events: TrackEvents = vec![(0, 0, meta_event(0x03, b"BGM"))];
+    for (&(tick, keysound), end) in bgm.iter().zip(short_note_ends(&bgm)) {
+        push_note(&mut bgm_events, tick, end, keysound);
+    }
+    tracks.push(track_chunk(bgm_events));
+    let notes = cbms.playable_notes(&bms.timing, &bms.stops);
+    for lane in 0 .. 2 * LANES_PER_SIDE {
+        let lane_notes: Vec<_> = notes.iter().filter(|n| n.lane == lane).collect();
+        if lane_notes.is_empty() { continue; }
+        let mut events: TrackEvents = vec![(0, 0, meta_event(0x03, lane_name(lane).as_bytes()))];
+        let note_ticks: Vec<(u64, u32)> = lane_notes.iter().map(|n| (ticks.tick(n.bms_time), lane)).collect();
+        let short_ends = short_note_ends(&note_ticks);
+        for (idx, note) in lane_notes.iter().enumerate() {
+            let tick = note_ticks[idx].0;
+            match note.kind {
+                NoteKind::Normal => push_note(&mut events, tick, short_ends[idx], note.keysound),
+                //Sustained until the end, which is the next note on the lane
+                NoteKind::LongStart => {
+                    let end = lane_notes.get(idx + 1).map_or(short_ends[idx], |end| ticks.tick(end.bms_time));
+                    push_note(&mut events, tick, end, note.keysound);
+                },
+                NoteKind::LongEnd => (),
+            }
+        }
+        tracks.push(track_chunk(events));
+    }
+    let mut smf = b"MThd".to_vec();
+    smf.extend(&6u32.to_be_bytes());
+    smf.extend(&1u16.to_be_bytes());
+    smf.extend(&(tracks.len() as u16).to_be_bytes());
+    smf.extend(&TICKS_PER_QUARTER.to_be_bytes());
+    for track in tracks {
+        smf.extend(track);
+    }
+    smf
+}
+
+pub fn export_midi_to_file(bms: &ImportedBMS, path: &str) -> Result<(), MidiError> {
+    let mut file = File::create(path)
+        .map_err(|_| MidiError::CouldntOpenFile)?;
+    file.write_all(&export_midi(bms))
+        .map_err(|_| MidiError::ErrorWritingFile)
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+struct MidiNote {
+    track: usize,
+    channel: u8,
+    note: u8,
+    start: u64,
+    end: u64,
+}
+
+//The parts of a file a chart is made from, ticks counted from the start of the file
+#[derive(Clone, PartialEq, Debug, Default)]
+struct MidiFile {
+    ticks_per_quarter: u64,
+    track_names: Vec<String>,
+    //Tick and microseconds per quarter note
+    tempos: Vec<(u64, u32)>,
+    //Tick, numerator and denominator
+    time_signatures: Vec<(u64, u32, u32)>,
+    texts: Vec<String>,
+    notes: Vec<MidiNote>,
+}
+
+struct MidiReader<'b> {
+    bytes: &'b [u8],
+    pos: usize,
+}
+
+impl<'b> MidiReader<'b> {
+    fn take(&mut self, len: usize) -> Result<&'b [u8], MidiError> {
+        let taken = self.bytes.get(self.pos .. self.pos + len).ok_or(MidiError::InvalidFormat)?;
+        self.pos += len;
+        Ok(taken)
+    }
+    fn byte(&mut self) -> Result<u8, MidiError> {
+        Ok(self.take(1)?[0])
+    }
+    fn u16(&mut self) -> Result<u16, MidiError> {
+        Ok(u16::from_be_bytes([self.byte()?, self.byte()?]))
+    }
+    fn u32(&mut self) -> Result<u32, MidiError> {
+        Ok(u32::from_be_bytes([self.byte()?, self.byte()?, self.byte()?, self.byte()?]))
+    }
+    fn vlq(&mut self) -> Result<u64, MidiError> {
+        let mut value = 0;
+        for _ in 0 .. 4 {
+            let byte = self.byte()?;
+            value = (value << 7) | (byte & 0x7F) as u64;
+            if byte & 0x80 == 0 { return Ok(value); }
+        }
+        Err(MidiError::InvalidFormat)
+    }
+}
+
+fn read_track(track: usize, data: &[u8], file: &mut MidiFile) -> Result<(), MidiError> {
+    let mut reader = MidiReader { bytes: data, pos: 0 };
+    let mut tick = 0;
+    let mut running_status = None;
+    let mut track_name = String::new();
+    //Notes still held, a note off ends the earliest one
+    let mut held: HashMap<(u8, u8), VecDeque<u64>> = HashMap::new();
+    while reader.pos < data.len() {
+        tick += reader.vlq()?;
+        let status = match reader.byte()? {
+            status if status >= 0x80 => status,
+            //Running status, the byte was the first data byte
+            _ => {
+                reader.pos -= 1;
+                running_status.ok_or(MidiError::InvalidFormat)?
+            },
+        };
+        match status {
+            0xFF => {
+                let kind = reader.byte()?;
+                let len = reader.vlq()? as usize;
+                let data = reader.take(len)?;
+                match (kind, data) {
+                    (0x2F, _) => break,
+                    (0x51, &[a, b, c]) => file.tempos.push((tick, u32::from_be_bytes([0, a, b, c]))),
+                    (0x58, &[numerator, denominator, ..]) => {
+                        if numerator == 0 || denominator > MAX_DENOMINATOR_POWER { return Err(MidiError::InvalidFormat); }
+                        file.time_signatures.push((tick, numerator as u32, 1 << denominator));
+                    },
+                    (0x03, name) if track_name.is_empty() => track_name = String::from_utf8_lossy(name).into_owned(),
+                    (0x01, text) => file.texts.push(String::from_utf8_lossy(text).into_owned()),
+                    _ => (),
+                }
+            },
+            0xF0 | 0xF7 => {
+                let len = reader.vlq()? as usize;
+                reader.take(len)?;
+                running_status = None;
+            },
+            _ => {
+                running_status = Some(status);
+                let channel = status & 0x0F;
+                match status & 0xF0 {
+                    0x80 | 0x90 => {
+                        let (note, velocity) = (reader.byte()?, reader.byte()?);
+                        if status & 0xF0 == 0x90 && velocity > 0 {
+                            held.entry((channel, note)).or_default().push_back(tick);
+                        } else if let Some(start) = held.get_mut(&(channel, note)).and_then(VecDeque::pop_front) {
+                            file.notes.push(MidiNote { track, channel, note, start, end: tick });
+                        }
+                    },
+                    0xC0 | 0xD0 => { reader.byte()?; },
+                    0xA0 | 0xB0 | 0xE0 => { reader.take(2)?; },
+                    _ => return Err(MidiError::InvalidFormat),
+                }
+            },
+        }
+    }
+    //Notes never released end where they start
+    for ((channel, note), starts) in held {
+        file.notes.extend(starts.into_iter().map(|start| MidiNote { track, channel, note, start, end: start }));
+    }
+    file.track_names.push(track_name);
+    Ok(())
+}
+
+fn read_midi(bytes: &[u8]) -> Result<MidiFile, MidiError> {
+    let mut reader = MidiReader { bytes, pos: 0 };
+    if reader.take(4)? != b"MThd" { return Err(MidiError::InvalidFormat); }
+    let header_len = reader.u32()? as usize;
+    let format = reader.u16()?;
+    let track_count = reader.u16()?;
+    let division = reader.u16()?;
+    reader.take(header_len.saturating_sub(6))?;
+    if format > 1 || division & 0x8000 != 0 || division == 0 { return Err(MidiError::UnsupportedFormat); }
+    let mut file = MidiFile { ticks_per_quarter: division as u64, ..Default::default() };
+    while file.track_names.len() < track_count as usize && reader.pos < bytes.len() {
+        let kind = reader.take(4)?;
+        let len = reader.u32()? as usize;
+        let data = reader.take(len)?;
+        //Chunks of other kinds are meant to be skipped
+        if kind == b"MTrk" {
+            read_track(file.track_names.len(), data, &mut file)?;
+        }
+    }
+    file.notes.sort_by_key(|n| (n.start, n.track));
+    Ok(file)
+}
+
+//Lanes 0-8 are channels 11-19, lanes 9-17 channels 21-29
+fn lane_channel(lane: u32) -> u32 {
+    11 + lane / LANES_PER_SIDE * 10 + lane % LANES_PER_SIDE
+}
+
+pub fn import_midi_from_file(path: &str, mapping: &MidiMapping) -> Result<ImportedBMS, MidiError> {
+    let mut file = File::open(path)
+        .map_err(|_| MidiError::CouldntOpenFile)?;
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes)
+        .map_err(|_| MidiError::ErrorReadingFile)?;
+    import_midi(&bytes, mapping)
+}
+
+//Makes a draft chart from a Standard MIDI File. Measures follow the time signatures, a change
+//in the middle of a measure taking effect from the next one, and BPMs follow the tempo map.
+//The first track name becomes the title and "WAVxx path" text events keysound definitions.
+//Files running past measure 999, or with time signatures finer than 64ths, are `InvalidFormat`.
+pub fn import_midi(bytes: &[u8], mapping: &MidiMapping) -> Result<ImportedBMS, MidiError> {
+    let midi = read_midi(bytes)?;
+    let last_tick = midi.notes.iter().map(|n| n.end)
+        .chain(midi.tempos.iter().map(|t| t.0))
+        .max()
+        .unwrap_or(0);
+    //Start tick, length in ticks and time signature of every measure
+    let mut measures: Vec<(u64, u64, (u32, u32))> = Vec::new();
+    let mut signatures = midi.time_signatures.clone();
+    signatures.sort_by_key(|s| s.0);
+    let (mut start, mut signature) = (0, (4, 4));
+    while start <= last_tick {
+        if measures.len() > MAX_MEASURE { return Err(MidiError::InvalidFormat); }
+        signature = signatures.iter().take_while(|s| s.0 <= start).last().map_or(signature, |s| (s.1, s.2));
+        let length = (midi.ticks_per_quarter * 4 * signature.0 as u64 / signature.1 as u64).max(1);
+        measures.push((start, length, signature));
+        start += length;
+    }
+    let position = |tick: u64| {
+        let measure = measures.iter().rposition(|m| m.0 <= tick).unwrap_or(0);
+        (measure as u32, (tick - measures[measure].0) as u32, measures[measure].1 as u32)
+    };
+    let mut tempos = midi.tempos.clone();
+    tempos.sort_by_key(|t| t.0);
+    //Tempos are whole microseconds, which puts whole BPMs slightly off
+    let bpm = |tempo: u32| ((60_000_000_000.0 / tempo.max(1) as f64).round() / 1000.0) as f32;
+    let initial_tempo = tempos.iter().take_while(|t| t.0 == 0).last().map_or(500_000, |t| t.1);
+    let mut builder = ChartBuilder::new(bpm(initial_tempo));
+    if let Some(title) = midi.track_names.first().filter(|name| !name.is_empty()) {
+        builder.set_title(title);
+    }
+    for text in &midi.texts {
+        let keysound = text.strip_prefix("WAV")
+            .and_then(|rest| rest.split_once(' '))
+            .and_then(|(idx, path)| Some((idx.parse::<u32>().ok()?, path)));
+        if let Some((idx, path)) = keysound {
+            builder.set_keysound(idx, path);
+        }
+    }
+    for (measure, &(_, _, (numerator, denominator))) in measures.iter().enumerate() {
+        let length = numerator as f64 / denominator as f64;
+        if length != 1.0 {
+            builder.set_measure_length(measure as u32, length);
+        }
+    }
+    for &(tick, tempo) in tempos.iter().filter(|t| t.0 > 0) {
+        let (measure, num, den) = position(tick);
+        builder.add_bpm_change(measure, num, den, bpm(tempo));
+    }
+    //Tick each lane is free again from
+    let mut lanes_free: HashMap<u32, u64> = HashMap::new();
+    for note in &midi.notes {
+        let track_name = &midi.track_names[note.track];
+        let rule = match mapping.rules.iter().find(|rule| rule.matches(note.track, track_name, note)) {
+            Some(rule) => rule,
+            None => continue,
+        };
+        let keysound = rule.keysound.unwrap_or_else(|| note_to_keysound(note.channel, note.note));
+        let (measure, num, den) = position(note.start);
+        match rule.target {
+            MidiTarget::Bgm => builder.add_object(measure, num, den, BGM_CHANNEL, keysound),
+            MidiTarget::Lane(lane) => {
+                //A lane can only have one note at a time, later ones overlapping it are left out
+                if lanes_free.get(&lane).is_some_and(|&free| note.start < free) { continue; }
+                let held = (note.end - note.start) as f64 / midi.ticks_per_quarter as f64;
+                let long = mapping.long_note_length.is_some_and(|length| held > length);
+                lanes_free.insert(lane, if long { note.end + 1 } else { note.start + 1 });
+                if long {
+                    let (end_measure, end_num, end_den) = position(note.end);
+                    //11 to 51, the long note channel of the same lane
+                    builder.add_object(measure, num, den, lane_channel(lane) + 40, keysound);
+                    builder.add_object(end_measure, end_num, end_den, lane_channel(lane) + 40, keysound);
+                } else {
+                    builder.add_object(measure, num, den, lane_channel(lane), keysound);
+                }
+            },
+        }
+    }
+    Ok(builder.build())
+}
+
+#[cfg(test)]
+fn find(bytes: &[u8], pattern: &[u8]) -> Option<usize> {
+    bytes.windows(pattern.len()).position(|window| window == pattern)
+}
+
+#[cfg(test)]
+#[test]
+fn test_export_midi() {
+    //A quarter note stop at 60 BPM, then a note on key 1 as measure 1 switches to 120 BPM
+    //and a long note on the scratch in measure 2
+    let bms = crate::compiler::import_bms(concat!(
+        "#BPM 60\n#WAV01 kick.wav\n#WAVZZ last.wav\n#STOP01 48\n",
+        "#00009:01\n#00101:ZZ\n#00111:01\n#00103:78\n#00256:0101\n",
+    )).unwrap();
+    let smf = export_midi(&bms);
+    assert_eq!(&smf[.. 14], &[b'M', b'T', b'h', b'd', 0, 0, 0, 6, 0, 1, 0, 4, 0x01, 0xE0]);
+    assert_eq!(smf.windows(4).filter(|w| w == b"MTrk").count(), 4);
+    //60 and 120 BPM
+    assert!(find(&smf, &[0xFF, 0x51, 0x03, 0x0F, 0x42, 0x40]).is_some());
+    assert!(find(&smf, &[0xFF, 0x51, 0x03, 0x07, 0xA1, 0x20]).is_some());
+    assert!(find(&smf, b"WAV1295 last.wav").is_some());
+    //The key 1 track: measure 0 and the stop are 5 quarter notes, 2400 ticks
+    let key_track = find(&smf, b"1P key 1").unwrap();
+    assert_eq!(&smf[key_track + 8 .. key_track + 13], &[0x92, 0x60, 0x90, 1, VELOCITY]);
+    //The BGM track: ZZ is channel 10, note 15
+    let bgm_track = find(&smf, b"BGM").unwrap();
+    assert_eq!(&smf[bgm_track + 3 .. bgm_track + 8], &[0x92, 0x60, 0x9A, 15, VELOCITY]);
+    //The long note starts 9 quarter notes in and lasts half a measure
+    let scratch_track = find(&smf, b"1P scratch").unwrap();
+    assert_eq!(&smf[scratch_track + 10 .. scratch_track + 20], &[0xA1, 0x60, 0x90, 1, VELOCITY, 0x87, 0x40, 0x80, 1, 0]);
+    assert_eq!(keysound_to_note(1295), (10, 15));
+    assert_eq!(note_to_keysound(10, 15), 1295);
+}
+
+#[cfg(test)]
+#[test]
+fn test_midi_round_trip() {
+    let bms = crate::compiler::import_bms(concat!(
+        "#TITLE Song\n#BPM 150\n#WAV01 kick.wav\n#WAV02 bgm.wav\n#STOP01 48\n",
+        "#00101:02\n#00102:0.75\n#00111:0101\n#00103:78\n#00109:0001\n#00219:0001\n#00256:01000001\n",
+    )).unwrap();
+    let reimported = import_midi(&export_midi(&bms), &MidiMapping::by_track_names()).unwrap();
+    assert_eq!(reimported.title, "Song");
+    assert_eq!(&reimported.resource_table[1 ..= 2], &["kick.wav", "bgm.wav"]);
+    assert_eq!(reimported.timing[1], (1.0.into(), 120.0, 3.0));
+    //The stop comes back as empty beats, so everything stays at the same time
+    let notes = |bms: &ImportedBMS| -> Vec<(u32, i64, u32, NoteKind)> {
+        bms.eval_and_compile().playable_notes(&bms.timing, &bms.stops).iter()
+            .map(|n| (n.lane, (n.time * 1000.0).round() as i64, n.keysound, n.kind))
+            .collect()
+    };
+    assert_eq!(notes(&bms).len(), 5);
+    assert_eq!(notes(&reimported), notes(&bms));
+    let bgm = |bms: &ImportedBMS| -> Vec<(i64, u32)> {
+        bms.eval_and_compile().timed_commands(&bms.timing, &bms.stops).iter()
+            .filter(|tc| matches!(tc.command.kind(), ChannelKind::Bgm))
+            .map(|tc| ((tc.time * 1000.0).round() as i64, tc.command.value))
+            .collect()
+    };
+    assert_eq!(bgm(&reimported), bgm(&bms));
+}
+
+#[cfg(test)]
+#[test]
+fn test_import_midi_with_mapping() {
+    //A format 0 drum loop in 3/4 at 90 BPM: kicks, snares and a held cymbal
+    let mut events: TrackEvents = vec![
+        (0, 0, meta_event(0x51, &[0x0A, 0x2C, 0x2B])),
+        (0, 0, meta_event(0x58, &[3, 2, 24, 8])),
+    ];
+    for (tick, note, length) in &[(0, 36, 60), (480, 38, 60), (960, 38, 60), (1440, 36, 60), (1440, 49, 960)] {
+        events.push((*tick, 1, vec![0x99, *note, VELOCITY]));
+        events.push((tick + length, 0, vec![0x99, *note, 0]));
+    }
+    let mut smf = b"MThd\0\0\0\x06\0\0\0\x01\x01\xE0".to_vec();
+    smf.extend(track_chunk(events));
+    let mapping = MidiMapping {
+        rules: vec![
+            MidiRule { notes: Some((35, 36)), keysound: Some(1), ..MidiRule::new(MidiTarget::Lane(5)) },
+            MidiRule { notes: Some((38, 38)), keysound: Some(2), ..MidiRule::new(MidiTarget::Lane(0)) },
+            MidiRule { channel: Some(9), keysound: Some(3), ..MidiRule::new(MidiTarget::Lane(1)) },
+        ],
+        long_note_length: Some(0.5),
+    };
+    let bms = import_midi(&smf, &mapping).unwrap();
+    assert_eq!(bms.bpm, 90.0);
+    assert_eq!(bms.timing[0], (0.0.into(), 90.0, 3.0));
+    let notes: Vec<(u32, f64, NoteKind)> = bms.eval_and_compile().playable_notes(&bms.timing, &bms.stops).iter()
+        .map(|n| (n.lane, (n.time * 1000.0).round() / 1000.0, n.kind))
+        .collect();
+    assert_eq!(notes, vec![
+        (5, 0.0, NoteKind::Normal),
+        (0, 0.667, NoteKind::Normal),
+        (0, 1.333, NoteKind::Normal),
+        (5, 2.0, NoteKind::Normal),
+        (1, 2.0, NoteKind::LongStart),
+        (1, 3.333, NoteKind::LongEnd),
+    ]);
+    assert_eq!(import_midi(b"MThd\0\0\0\x06\0\x02\0\x01\x01\xE0", &mapping).unwrap_err(), MidiError::UnsupportedFormat);
+}
+
+#[cfg(test)]
+#[test]
+fn test_import_midi_limits() {
+    let smf = |signature: [u8; 4], note_tick: u64| {
+        let events: TrackEvents = vec![
+            (0, 0, meta_event(0x58, &signature)),
+            (note_tick, 1, vec![0x90, 60, VELOCITY]),
+            (note_tick + 60, 0, vec![0x90, 60, 0]),
+        ];
+        let mut smf = b"MThd\0\0\0\x06\0\0\0\x01\x01\xE0".to_vec();
+        smf.extend(track_chunk(events));
+        import_midi(&smf, &MidiMapping::by_track_names())
+    };
+    //Measure 999 in 4/4 is the last one a chart has
+    let measure_ticks = 4 * TICKS_PER_QUARTER as u64;
+    assert!(smf([4, 2, 24, 8], 999 * measure_ticks).is_ok());
+    assert_eq!(smf([4, 2, 24, 8], 1000 * measure_ticks).unwrap_err(), MidiError::InvalidFormat);
+    //Down to 64ths, 1/128 and beyond would make measures next to no ticks long
+    assert!(smf([1, 6, 24, 8], 0).is_ok());
+    assert_eq!(smf([1, 7, 24, 8], 0).unwrap_err(), MidiError::InvalidFormat);
+    assert_eq!(smf([1, 40, 24, 8], 0).unwrap_err(), MidiError::InvalidFormat);
+    assert_eq!(smf([0, 2, 24, 8], 0).unwrap_err(), MidiError::InvalidFormat);
+}
+
+#[cfg(test)]
+#[test]
+fn test_midi_odd_measure_lengths() {
+    assert_eq!((time_signature(4.0), time_signature(3.0), time_signature(1.5), time_signature(0.25)), ((4, 2), (3, 2), (3, 3), (1, 4)));
+    let bms = crate::compiler::import_bms(concat!(
+        "#BPM 150\n#WAV01 kick.wav\n",
+        "#00002:0.3\n#00011:0101\n#00102:0.375\n#00111:01\n#00211:01\n",
+    )).unwrap();
+    let smf = export_midi(&bms);
+    //150 BPM all along, 0.3 of a measure rounded to 5/16 and 0.375 as 3/8
+    assert_eq!(smf.windows(3).filter(|w| w == &[0xFF, 0x51, 0x03]).count(), 3);
+    assert!(find(&smf, &[0xFF, 0x51, 0x03, 0x06, 0x1A, 0x80]).is_some());
+    assert!(find(&smf, &[0xFF, 0x58, 0x04, 5, 4, 24, 8]).is_some());
+    assert!(find(&smf, &[0xFF, 0x58, 0x04, 3, 3, 24, 8]).is_some());
+    assert!(find(&smf, &[0xFF, 0x58, 0x04, 4, 2, 24, 8]).is_some());
+    let reimported = import_midi(&smf, &MidiMapping::by_track_names()).unwrap();
+    assert!(reimported.timing.iter().all(|t| t.1 == 150.0));
+    let times = |bms: &ImportedBMS| -> Vec<i64> {
+        bms.eval_and_compile().playable_notes(&bms.timing, &bms.stops).iter()
+            .map(|n| (n.time * 1000.0).round() as i64)
+            .collect()
+    };
+    assert_eq!(times(&bms), vec![0, 240, 480, 1080]);
+    assert_eq!(times(&reimported), times(&bms));
+}
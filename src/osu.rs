@@ -0,0 +1,397 @@
+use crate::cbms::{BGM_CHANNEL, ChannelKind, LANES_PER_SIDE, NoteKind};
+use crate::compiler::{ChartBuilder, ImportedBMS, SILENT_KEYSOUND, beat_position};
+use crate::shuffle::PlayStyle;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write};
+
+//osu! places mania notes by x, the playfield being this wide whatever the key count
+pub const PLAYFIELD_WIDTH: u32 = 512;
+const MUSIC_KEYSOUND: u32 = 1;
+const HOLD_TYPE: u32 = 128;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum OsuError {
+    CouldntOpenFile,
+    ErrorReadingFile,
+    ErrorWritingFile,
+    InvalidFormat,
+    //Beatmaps for the other modes have no columns
+    NotMania,
+    UnsupportedKeyCount(u32),
+}
+
+//Note channels of the columns from left to right. Scratch columns come first on
+//the 1P side and last on the 2P side, which is also how charts are exported.
+pub fn column_channels(keys: u32) -> Option<&'static [u32]> {
+    match keys {
+        4 => Some(&[11, 12, 13, 14]),
+        5 => Some(&[11, 12, 13, 14, 15]),
+        6 => Some(&[16, 11, 12, 13, 14, 15]),
+        7 => Some(&[11, 12, 13, 14, 15, 18, 19]),
+        8 => Some(&[16, 11, 12, 13, 14, 15, 18, 19]),
+        //Pop'n, laid out like .pms files
+        9 => Some(&[11, 12, 13, 14, 15, 22, 23, 24, 25]),
+        10 => Some(&[11, 12, 13, 14, 15, 21, 22, 23, 24, 25]),
+        12 => Some(&[16, 11, 12, 13, 14, 15, 21, 22, 23, 24, 25, 26]),
+        14 => Some(&[11, 12, 13, 14, 15, 18, 19, 21, 22, 23, 24, 25, 28, 29]),
+        16 => Some(&[16, 11, 12, 13, 14, 15, 18, 19, 21, 22, 23, 24, 25, 28, 29, 26]),
+        _ => None,
+    }
+}
+
+//Sections of the file, with [General] style key: value lines split
+#[derive(Clone, PartialEq, Debug, Default)]
+struct OsuSections<'s> {
+    values: HashMap<&'s str, &'s str>,
+    lists: HashMap<&'s str, Vec<&'s str>>,
+}
+
+fn sections(raw_osu: &str) -> OsuSections<'_> {
+    let mut sections = OsuSections::default();
+    let mut section = "";
+    for line in raw_osu.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with("//") { continue; }
+        if line.starts_with('[') && line.ends_with(']') {
+            section = &line[1 .. line.len() - 1];
+            continue;
+        }
+        match section {
+            "General" | "Metadata" | "Difficulty" => if let Some((key, value)) = line.split_once(':') {
+                sections.values.insert(key.trim(), value.trim());
+            },
+            _ => sections.lists.entry(section).or_default().push(line),
+        }
+    }
+    sections
+}
+
+//The file name at the end of a hitSample, normalSet:additionSet:index:volume:filename
+fn sample_file(hit_sample: &str) -> Option<&str> {
+    hit_sample.split(':').nth(4).map(str::trim).filter(|file| !file.is_empty())
+}
+
+//Uninherited timing points as (time in ms, ms per beat) and the beat each falls on
+struct OsuTiming {
+    points: Vec<(f64, f64)>,
+    beats: Vec<f64>,
+}
+
+impl OsuTiming {
+    //The first timing point goes on a measure line, with measures put in front of it
+    //if the music starts earlier
+    fn new(points: Vec<(f64, f64)>) -> Self {
+        let (first_time, first_length) = points[0];
+        let lead_in = (first_time / (4.0 * first_length)).ceil().max(0.0);
+        let mut beats = vec![4.0 * lead_in];
+        for window in points.windows(2) {
+            let (time, length) = window[0];
+            beats.push(beats[beats.len() - 1] + (window[1].0 - time) / length);
+        }
+        Self { points, beats }
+    }
+    fn beat_at(&self, time: f64) -> f64 {
+        let idx = self.points.iter().take_while(|(at, _)| *at <= time).count().max(1) - 1;
+        let (at, length) = self.points[idx];
+        self.beats[idx] + (time - at) / length
+    }
+}
+
+pub fn import_osu_from_file(path: &str) -> Result<ImportedBMS, OsuError> {
+    let mut file = File::open(path)
+        .map_err(|_| OsuError::CouldntOpenFile)?;
+    let mut raw_osu = String::new();
+    file.read_to_string(&mut raw_osu)
+        .map_err(|_| OsuError::ErrorReadingFile)?;
+    import_osu(&raw_osu)
+}
+
+//Converts an osu!mania beatmap. Hitsound files become keysounds, storyboard samples BGM,
+//and the audio file a BGM object where the audio starts.
+pub fn import_osu(raw_osu: &str) -> Result<ImportedBMS, OsuError> {
+    let sections = sections(raw_osu);
+    let value = |key: &str| sections.values.get(key).copied().unwrap_or("");
+    let list = |section: &str| sections.lists.get(section).map_or(&[][..], Vec::as_slice);
+    if !raw_osu.trim_start().starts_with("osu file format") { return Err(OsuError::InvalidFormat); }
+    if value("Mode") != "3" { return Err(OsuError::NotMania); }
+    let keys = value("CircleSize").parse::<f64>().map_err(|_| OsuError::InvalidFormat)?.round() as u32;
+    let columns = column_channels(keys).ok_or(OsuError::UnsupportedKeyCount(keys))?;
+    //time,beatLength,meter,sampleSet,sampleIndex,volume,uninherited,effects
+    let mut points: Vec<(f64, f64)> = list("TimingPoints").iter()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+            let uninherited = fields.get(6).is_none_or(|u| *u == "1");
+            let (time, length) = (fields.first()?.parse().ok()?, fields.get(1)?.parse().ok()?);
+            Some((time, length)).filter(|&(time, length): &(f64, f64)| uninherited && time.is_finite() && length.is_finite() && length > 0.0)
+        })
+        .collect();
+    if points.is_empty() { return Err(OsuError::InvalidFormat); }
+    points.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let timing = OsuTiming::new(points);
+    let mut builder = ChartBuilder::new((60000.0 / timing.points[0].1) as f32);
+    let title = value("Title");
+    builder.set_title(&match value("Version") {
+        "" => title.to_string(),
+        version => format!("{} [{}]", title, version),
+    });
+    for (&(_, length), &beat) in timing.points.iter().zip(&timing.beats).skip(1) {
+        let (measure, num, den) = beat_position(beat);
+        builder.add_bpm_change(measure, num, den, (60000.0 / length) as f32);
+    }
+    if !value("AudioFilename").is_empty() {
+        builder.set_keysound(MUSIC_KEYSOUND, value("AudioFilename"));
+        let (measure, num, den) = beat_position(timing.beat_at(0.0));
+        builder.add_object(measure, num, den, BGM_CHANNEL, MUSIC_KEYSOUND);
+    }
+    let mut keysounds: HashMap<String, u32> = HashMap::new();
+    let mut keysound = |builder: &mut ChartBuilder, file: Option<&str>| match file {
+        Some(file) => {
+            let next_idx = MUSIC_KEYSOUND + 1 + keysounds.len() as u32;
+            *keysounds.entry(file.to_string()).or_insert_with(|| {
+                builder.set_keysound(next_idx, file);
+                next_idx
+            })
+        },
+        None => SILENT_KEYSOUND,
+    };
+    //Sample,time,layer,"filename",volume
+    for line in list("Events") {
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        if let ["Sample", time, _, file, ..] = fields[..] {
+            if let Ok(time) = time.parse::<f64>() {
+                let value = keysound(&mut builder, Some(file.trim_matches('"')));
+                let (measure, num, den) = beat_position(timing.beat_at(time));
+                builder.add_object(measure, num, den, BGM_CHANNEL, value);
+            }
+        }
+    }
+    //x,y,time,type,hitSound,objectParams,hitSample. Holds have endTime:hitSample in place of both.
+    for line in list("HitObjects") {
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        let (x, time, kind) = match fields[..] {
+            [x, _, time, kind, ..] => match (x.parse::<f64>(), time.parse::<f64>(), kind.parse::<u32>()) {
+                (Ok(x), Ok(time), Ok(kind)) => (x, time, kind),
+                _ => return Err(OsuError::InvalidFormat),
+            },
+            _ => return Err(OsuError::InvalidFormat),
+        };
+        let column = ((x * keys as f64 / PLAYFIELD_WIDTH as f64).floor().max(0.0) as usize).min(keys as usize - 1);
+        let channel = columns[column];
+        let extra = fields.get(5).copied().unwrap_or("");
+        let head = beat_position(timing.beat_at(time));
+        if kind & HOLD_TYPE != 0 {
+            let (end, hit_sample) = extra.split_once(':').unwrap_or((extra, ""));
+            let end = end.parse::<f64>().map_err(|_| OsuError::InvalidFormat)?;
+            let value = keysound(&mut builder, sample_file(hit_sample));
+            let tail = beat_position(timing.beat_at(end));
+            //11 to 51, the long note channel of the same lane
+            builder.add_object(head.0, head.1, head.2, channel + 40, value);
+            builder.add_object(tail.0, tail.1, tail.2, channel + 40, value);
+        } else {
+            let value = keysound(&mut builder, sample_file(extra));
+            builder.add_object(head.0, head.1, head.2, channel, value);
+        }
+    }
+    Ok(builder.build())
+}
+
+//Lanes of the chart from the leftmost column to the rightmost
+fn export_lanes(style: PlayStyle, scratch: bool) -> Vec<u32> {
+    let mut lanes = Vec::new();
+    for side in 0 .. style.sides() {
+        let keys = style.key_lanes(side);
+        match (scratch, side) {
+            (false, _) => lanes.extend(keys),
+            (true, 0) => lanes.extend(std::iter::once(style.scratch_lane(side)).chain(keys)),
+            (true, _) => lanes.extend(keys.into_iter().chain(std::iter::once(style.scratch_lane(side)))),
+        }
+    }
+    lanes
+}
+
+fn ms(time: f64) -> i64 {
+    (time * 1000.0).round() as i64
+}
+
+//Writes the chart as an osu!mania beatmap, keysounds turned into hitsound and storyboard
+//sample files. With `audio_filename` BGM objects are left out, as the audio file should
+//hold them already. Notes on lanes osu! has no column for, mines and invisible notes are dropped.
+pub fn export_osu(bms: &ImportedBMS, audio_filename: Option<&str>) -> String {
+    let cbms = bms.eval_and_compile();
+    let notes = cbms.playable_notes(&bms.timing, &bms.stops);
+    let style = PlayStyle::detect(&notes);
+    let scratch = notes.iter().any(|n| n.lane % LANES_PER_SIDE == style.scratch_lane(0));
+    let lanes = export_lanes(style, scratch);
+    let file = |keysound: u32| bms.resource_table.get(keysound as usize).map_or("", String::as_str);
+    let mut osu = String::from("osu file format v14\n\n");
+    osu += "[General]\n";
+    osu += &format!("AudioFilename: {}\n", audio_filename.unwrap_or("virtual"));
+    osu += "AudioLeadIn: 0\nPreviewTime: -1\nCountdown: 0\nMode: 3\n\n";
+    osu += "[Metadata]\n";
+    osu += &format!("Title:{}\nTitleUnicode:{}\nArtist:\nCreator:\n", bms.title, bms.title);
+    osu += &match bms.playlevel {
+        Some(level) => format!("Version:Lv.{}\n\n", level),
+        None => "Version:BMS\n\n".to_string(),
+    };
+    //Stricter #RANKs get a higher OD
+    let od = 9 - bms.rank.min(3);
+    osu += "[Difficulty]\n";
+    osu += &format!("HPDrainRate:8\nCircleSize:{}\nOverallDifficulty:{}\nApproachRate:5\nSliderMultiplier:1.4\nSliderTickRate:1\n\n", lanes.len(), od);
+    osu += "[Events]\n//Storyboard Sound Samples\n";
+    if audio_filename.is_none() {
+        for tc in cbms.timed_commands(&bms.timing, &bms.stops) {
+            if matches!(tc.command.kind(), ChannelKind::Bgm) && !file(tc.command.value).is_empty() {
+                osu += &format!("Sample,{},0,\"{}\",100\n", ms(tc.time), file(tc.command.value));
+            }
+        }
+    }
+    //Stops have no equivalent, the scroll slows to a crawl during them instead
+    let mut points: Vec<(i64, String)> = Vec::new();
+    let mut last_bpm = None;
+    for (bms_time, bpm, _) in &bms.timing {
+        if last_bpm == Some(*bpm) { continue; }
+        last_bpm = Some(*bpm);
+        let time = ms(bms_time.to_absolute_time_with_stops(&bms.timing, &bms.stops));
+        points.push((time, format!("{},{},4,0,0,100,1,0", time, 60000.0 / *bpm as f64)));
+    }
+    for (bms_time, length) in &bms.stops {
+        let start = bms_time.to_absolute_time_with_stops(&bms.timing, &bms.stops);
+        let bpm = bms.timing.iter().take_while(|(at, _, _)| at <= bms_time).last().map_or(bms.bpm, |t| t.1);
+        points.push((ms(start), format!("{},-10000,4,0,0,100,0,0", ms(start))));
+        points.push((ms(start + length), format!("{},{},4,0,0,100,1,0", ms(start + length), 60000.0 / bpm as f64)));
+    }
+    points.sort_by_key(|(time, _)| *time);
+    osu += "\n[TimingPoints]\n";
+    for (_, point) in points {
+        osu += &point;
+        osu += "\n";
+    }
+    osu += "\n[HitObjects]\n";
+    let mut hold_starts = vec![None; 2 * LANES_PER_SIDE as usize];
+    let mut objects: Vec<(i64, String)> = Vec::new();
+    for note in &notes {
+        let column = match lanes.iter().position(|&lane| lane == note.lane) {
+            Some(column) => column as u32,
+            None => continue,
+        };
+        let x = (2 * column + 1) * PLAYFIELD_WIDTH / (2 * lanes.len() as u32);
+        match note.kind {
+            NoteKind::Normal => objects.push((ms(note.time), format!("{},192,{},1,0,0:0:0:0:{}", x, ms(note.time), file(note.keysound)))),
+            NoteKind::LongStart => hold_starts[note.lane as usize] = Some(note),
+            NoteKind::LongEnd => if let Some(start) = hold_starts[note.lane as usize].take() {
+                objects.push((ms(start.time), format!("{},192,{},{},0,{}:0:0:0:0:{}", x, ms(start.time), HOLD_TYPE, ms(note.time), file(start.keysound))));
+            },
+        }
+    }
+    objects.sort_by_key(|(time, _)| *time);
+    for (_, object) in objects {
+        osu += &object;
+        osu += "\n";
+    }
+    osu
+}
+
+pub fn export_osu_to_file(bms: &ImportedBMS, audio_filename: Option<&str>, path: &str) -> Result<(), OsuError> {
+    let mut file = File::create(path)
+        .map_err(|_| OsuError::CouldntOpenFile)?;
+    file.write_all(export_osu(bms, audio_filename).as_bytes())
+        .map_err(|_| OsuError::ErrorWritingFile)
+}
+
+#[cfg(test)]
+const TEST_BEATMAP: &str = "osu file format v14
+
+[General]
+AudioFilename: audio.mp3
+Mode: 3
+
+[Metadata]
+Title:Song
+Version:Hard
+
+[Difficulty]
+CircleSize:7
+OverallDifficulty:8
+
+[Events]
+Sample,1000,0,\"clap.wav\",100
+
+[TimingPoints]
+500,500,4,2,0,100,1,0
+1500,-50,4,2,0,100,0,0
+2500,250,4,2,0,100,1,0
+
+[HitObjects]
+36,192,500,1,0,0:0:0:0:kick.wav
+475,192,1000,128,0,2000:0:0:0:0:
+256,192,3000,1,0,0:0:0:0:kick.wav
+";
+
+#[cfg(test)]
+#[test]
+fn test_import_osu() {
+    let bms = import_osu(TEST_BEATMAP).unwrap();
+    assert_eq!((bms.title.as_str(), bms.bpm), ("Song [Hard]", 120.0));
+    assert_eq!(&bms.resource_table[1 ..= 3], &["audio.mp3", "clap.wav", "kick.wav"]);
+    let cbms = bms.eval_and_compile();
+    //A measure of lead in puts the first timing point, and the first note, at 2 seconds
+    let notes: Vec<(u32, f64, u32, NoteKind)> = cbms.playable_notes(&bms.timing, &bms.stops).iter()
+        .map(|n| (n.lane, n.time, n.keysound, n.kind))
+        .collect();
+    assert_eq!(notes, vec![
+        (0, 2.0, 3, NoteKind::Normal),
+        (8, 2.5, SILENT_KEYSOUND, NoteKind::LongStart),
+        (8, 3.5, SILENT_KEYSOUND, NoteKind::LongEnd),
+        (3, 4.5, 3, NoteKind::Normal),
+    ]);
+    let bgm: Vec<(f64, u32)> = cbms.timed_commands(&bms.timing, &bms.stops).iter()
+        .filter(|tc| matches!(tc.command.kind(), ChannelKind::Bgm))
+        .map(|tc| (tc.time, tc.command.value))
+        .collect();
+    assert_eq!(bgm, vec![(1.5, 1), (2.5, 2)]);
+    assert_eq!(import_osu(&TEST_BEATMAP.replace("Mode: 3", "Mode: 0")).unwrap_err(), OsuError::NotMania);
+    assert_eq!(import_osu(&TEST_BEATMAP.replace("CircleSize:7", "CircleSize:3")).unwrap_err(), OsuError::UnsupportedKeyCount(3));
+    //Timing points that aren't finite numbers are left out
+    let odd_points = TEST_BEATMAP.replace("[TimingPoints]\n", "[TimingPoints]\nnan,500,4,2,0,100,1,0\n700,inf,4,2,0,100,1,0\n");
+    assert_eq!(import_osu(&odd_points).unwrap().timing, bms.timing);
+    assert_eq!(import_osu(&TEST_BEATMAP.replace("500,500,4", "nan,500,4").replace("2500,250,4", "NaN,250,4")).unwrap_err(), OsuError::InvalidFormat);
+}
+
+#[cfg(test)]
+#[test]
+fn test_export_osu_round_trip() {
+    let bms = crate::compiler::import_bms(concat!(
+        "#TITLE Song\n#PLAYLEVEL 5\n#BPM 120\n#WAV01 kick.wav\n#WAV02 bgm.wav\n#STOP01 96\n",
+        "#00101:02\n#00116:01\n#00111:0001\n#00119:0000000001\n#00151:00010100\n#00209:01\n#00211:0101\n",
+    )).unwrap();
+    let osu = export_osu(&bms, None);
+    assert!(osu.contains("CircleSize:8\n") && osu.contains("Version:Lv.5\n"));
+    assert!(osu.contains("Sample,2000,0,\"bgm.wav\",100\n"));
+    //The stop starts at 4 seconds and lasts a second
+    assert!(osu.contains("4000,-10000,4,0,0,100,0,0\n5000,500,4,0,0,100,1,0\n"));
+    let reimported = import_osu(&osu).unwrap();
+    let times = |bms: &ImportedBMS| -> Vec<(u32, i64, NoteKind)> {
+        bms.eval_and_compile().playable_notes(&bms.timing, &bms.stops).iter()
+            .map(|n| (n.lane, ms(n.time), n.kind))
+            .collect()
+    };
+    let original = times(&bms);
+    assert_eq!(original.len(), 7);
+    assert_eq!(times(&reimported), original);
+}
+
+#[cfg(test)]
+#[test]
+fn test_export_osu_measure_length() {
+    let bms = crate::compiler::import_bms("#BPM 120\n#00002:0.3\n#00011:01\n#00111:01\n#00211:01\n").unwrap();
+    let osu = export_osu(&bms, Some("song.ogg"));
+    //Odd measure lengths keep the chart's tempo, 500ms a beat
+    let points: Vec<&str> = osu.lines().skip_while(|l| *l != "[TimingPoints]").skip(1).take_while(|l| !l.is_empty()).collect();
+    assert_eq!(points, vec!["0,500,4,0,0,100,1,0"]);
+    let reimported = import_osu(&osu).unwrap();
+    let times: Vec<i64> = reimported.eval_and_compile().playable_notes(&reimported.timing, &reimported.stops).iter()
+        .map(|n| ms(n.time))
+        .collect();
+    assert_eq!(times, vec![0, 600, 2600]);
+}
@@ -0,0 +1,189 @@
+use crate::bms::{BMSTime, BMSTimings, BMSStops};
+use crate::cbms::{CBMS, ChannelKind, LANES_PER_SIDE, NoteKind, PlayableNote};
+use crate::difficulty::JACK_WINDOW;
+
+//Sections with fewer hits than this are left unlabeled, besides soflan
+pub const MIN_SECTION_HITS: usize = 4;
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum Pattern {
+    Jacks,
+    Trills,
+    Stairs,
+    Chords,
+    ScratchHeavy,
+    LongNotes,
+    //BPM changes and stops
+    Soflan,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct SectionPatterns {
+    //None for sections that are time windows rather than measures
+    pub measure: Option<u32>,
+    pub start: f64,
+    pub end: f64,
+    pub patterns: Vec<Pattern>,
+}
+
+//Position of a key from left to right within its side, None for the scratch and the free zone
+fn key_position(lane: u32) -> Option<i32> {
+    match lane % LANES_PER_SIDE {
+        key @ 0 ..= 4 => Some(key as i32),
+        key @ 7 ..= 8 => Some(key as i32 - 2),
+        _ => None,
+    }
+}
+
+//Labels one section from the notes hit in it and the number of speed changes inside
+pub fn classify_section(hits: &[&PlayableNote], speed_changes: usize) -> Vec<Pattern> {
+    let mut patterns = Vec::new();
+    if hits.len() >= MIN_SECTION_HITS {
+        let total = hits.len() as f64;
+        let (mut jacks, mut trills, mut stairs, mut chorded) = (0, 0, 0, 0);
+        let mut last_hit: Vec<Option<f64>> = vec![None; 2 * LANES_PER_SIDE as usize];
+        let mut singles: Vec<&PlayableNote> = Vec::new();
+        let mut stair_run = 0;
+        let mut beg = 0;
+        while beg < hits.len() {
+            let time = hits[beg].time;
+            let end = beg + hits[beg ..].iter().take_while(|n| n.time == time).count();
+            for note in &hits[beg .. end] {
+                if last_hit[note.lane as usize].is_some_and(|last| time - last <= JACK_WINDOW) {
+                    jacks += 1;
+                }
+                last_hit[note.lane as usize] = Some(time);
+            }
+            if end - beg > 1 {
+                chorded += end - beg;
+                singles.clear();
+                stair_run = 0;
+            } else {
+                let note = hits[beg];
+                if let [.., a, b] = singles[..] {
+                    if a.lane == note.lane && b.lane != note.lane {
+                        trills += 1;
+                    }
+                }
+                //Three or more neighbouring keys in a row going the same way
+                let step = singles.last().and_then(|last| Some(key_position(note.lane)? - key_position(last.lane)?));
+                let last_step = match singles[..] {
+                    [.., a, b] => key_position(b.lane).zip(key_position(a.lane)).map(|(b, a)| b - a),
+                    _ => None,
+                };
+                if matches!(step, Some(1) | Some(-1)) && step == last_step {
+                    stair_run += 1;
+                    stairs += if stair_run == 1 { 3 } else { 1 };
+                } else {
+                    stair_run = 0;
+                }
+                singles.push(note);
+            }
+            beg = end;
+        }
+        let scratch = hits.iter().filter(|n| n.lane % LANES_PER_SIDE == 5).count();
+        let long_notes = hits.iter().filter(|n| n.kind == NoteKind::LongStart).count();
+        let labels = [
+            (Pattern::Jacks, jacks as f64 / total >= 0.3),
+            (Pattern::Trills, trills as f64 / total >= 0.4),
+            (Pattern::Stairs, stairs as f64 / total >= 0.4),
+            (Pattern::Chords, chorded as f64 / total >= 0.5),
+            (Pattern::ScratchHeavy, scratch as f64 / total >= 0.25),
+            (Pattern::LongNotes, long_notes as f64 / total >= 0.3),
+        ];
+        patterns.extend(labels.iter().filter(|(_, matched)| *matched).map(|(pattern, _)| *pattern));
+    }
+    if speed_changes > 0 {
+        patterns.push(Pattern::Soflan);
+    }
+    patterns
+}
+
+//BPM changes and stops with their chart position
+fn speed_changes(cbms: &CBMS, timings: &BMSTimings, stops: &BMSStops) -> Vec<(BMSTime, f64)> {
+    let mut changes: Vec<(BMSTime, f64)> = cbms.timed_commands(timings, stops)
+        .into_iter()
+        .filter(|tc| matches!(tc.command.kind(), ChannelKind::Bpm | ChannelKind::ExtendedBpm))
+        .map(|tc| (tc.bms_time, tc.time))
+        .collect();
+    changes.extend(stops.iter().map(|(bms_time, _)| (*bms_time, bms_time.to_absolute_time_with_stops(timings, stops))));
+    changes
+}
+
+fn hits(cbms: &CBMS, timings: &BMSTimings, stops: &BMSStops) -> Vec<PlayableNote> {
+    cbms.playable_notes(timings, stops)
+        .into_iter()
+        .filter(|n| n.kind != NoteKind::LongEnd)
+        .collect()
+}
+
+pub fn classify_measures(cbms: &CBMS, timings: &BMSTimings, stops: &BMSStops) -> Vec<SectionPatterns> {
+    let hits = hits(cbms, timings, stops);
+    let changes = speed_changes(cbms, timings, stops);
+    (0 .. cbms.bar_count() as u32)
+        .map(|measure| {
+            let in_measure: Vec<&PlayableNote> = hits.iter().filter(|n| n.bms_time.bar() == measure as usize).collect();
+            let speed_changes = changes.iter().filter(|(bms_time, _)| bms_time.bar() == measure as usize).count();
+            SectionPatterns {
+                measure: Some(measure),
+                start: BMSTime::from(measure as f64).to_absolute_time_with_stops(timings, stops),
+                end: BMSTime::from(measure as f64 + 1.0).to_absolute_time_with_stops(timings, stops),
+                patterns: classify_section(&in_measure, speed_changes),
+            }
+        })
+        .collect()
+}
+
+//Same as `classify_measures`, over back to back `window` seconds long sections
+pub fn classify_windows(cbms: &CBMS, timings: &BMSTimings, stops: &BMSStops, window: f64) -> Vec<SectionPatterns> {
+    let hits = hits(cbms, timings, stops);
+    let changes = speed_changes(cbms, timings, stops);
+    let length = hits.iter().map(|n| n.time).chain(changes.iter().map(|c| c.1)).fold(0.0, f64::max);
+    let count = (length / window).floor() as usize + 1;
+    (0 .. count)
+        .map(|idx| {
+            let (start, end) = (idx as f64 * window, (idx + 1) as f64 * window);
+            let in_window: Vec<&PlayableNote> = hits.iter().filter(|n| start <= n.time && n.time < end).collect();
+            let speed_changes = changes.iter().filter(|(_, time)| start <= *time && *time < end).count();
+            SectionPatterns {
+                measure: None,
+                start,
+                end,
+                patterns: classify_section(&in_window, speed_changes),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+#[test]
+fn test_classify_measures() {
+    //Measure 0 is a jack, 1 a trill, 2 stairs, 3 chords, 4 scratch with a BPM change
+    let bms = crate::compiler::import_bms(concat!(
+        "#BPM 120\n",
+        "#00011:0101010101010101\n",
+        "#00111:0100010001000100\n#00112:0001000100010001\n",
+        "#00211:0100000000000001\n#00212:0001000000000100\n#00213:0000010000010000\n#00214:0000000101000000\n",
+        "#00311:01010101\n#00313:01010101\n#00315:01010101\n",
+        "#00416:01010101\n#00411:0001000000010000\n#00403:3C\n",
+    )).unwrap();
+    let sections = classify_measures(&bms.eval_and_compile(), &bms.timing, &bms.stops);
+    let patterns: Vec<Vec<Pattern>> = sections.iter().map(|s| s.patterns.clone()).collect();
+    assert_eq!(patterns, vec![
+        vec![Pattern::Jacks],
+        vec![Pattern::Trills],
+        vec![Pattern::Stairs],
+        vec![Pattern::Chords],
+        vec![Pattern::ScratchHeavy, Pattern::Soflan],
+    ]);
+    assert_eq!((sections[1].start, sections[1].end), (2.0, 4.0));
+}
+
+#[cfg(test)]
+#[test]
+fn test_classify_windows() {
+    let bms = crate::compiler::import_bms("#BPM 240\n#00051:0101\n#00052:00010001\n#00053:0001000001000000\n#00054:0000000000010001").unwrap();
+    let sections = classify_windows(&bms.eval_and_compile(), &bms.timing, &bms.stops, 1.0);
+    assert_eq!(sections.len(), 1);
+    assert_eq!(sections[0].patterns, vec![Pattern::LongNotes]);
+}
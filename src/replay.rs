@@ -0,0 +1,250 @@
+use crate::cbms::CBMS;
+use crate::compiler::ImportedBMS;
+use crate::gauge::{Gauge, GaugeType};
+use crate::judge::{JudgeResult, JudgeWindows, KeyEvent, judge_inputs};
+use crate::score::Score;
+use crate::shuffle::{LaneOption, PlayStyle, apply_lane_options};
+
+use std::convert::TryFrom;
+use std::io::{Read, Write};
+use std::path::Path;
+
+pub const REPLAY_MAGIC: &[u8; 4] = b"MBRP";
+pub const REPLAY_VERSION: u8 = 2;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ReplayError {
+    CouldntOpenFile,
+    ErrorReadingFile,
+    ErrorWritingFile,
+    InvalidFormat,
+    UnsupportedVersion,
+}
+
+//Everything needed to reproduce a play. Input times are kept in whole
+//microseconds so a replay judges the same before and after being saved.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Replay {
+    //MD5 of the chart file the play was made on
+    pub chart_hash: [u8; 16],
+    pub seed: u64,
+    //Key mode the chart was played in, which decides the lanes the options shuffle
+    pub play_style: PlayStyle,
+    //Option of each side, shuffled with `seed`
+    pub lane_options: [LaneOption; 2],
+    pub gauge_type: GaugeType,
+    pub inputs: Vec<KeyEvent>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ReplayResult {
+    pub results: Vec<JudgeResult>,
+    pub score: Score,
+    pub gauge: Gauge,
+}
+
+fn quantize(time: f64) -> u64 {
+    (time.max(0.0) * 1_000_000.0).round() as u64
+}
+
+fn gauge_code(gauge_type: GaugeType) -> u8 {
+    match gauge_type {
+        GaugeType::AssistEasy => 0,
+        GaugeType::Easy => 1,
+        GaugeType::Normal => 2,
+        GaugeType::Hard => 3,
+        GaugeType::ExHard => 4,
+        GaugeType::Hazard => 5,
+    }
+}
+
+fn gauge_from_code(code: u8) -> Option<GaugeType> {
+    match code {
+        0 => Some(GaugeType::AssistEasy),
+        1 => Some(GaugeType::Easy),
+        2 => Some(GaugeType::Normal),
+        3 => Some(GaugeType::Hard),
+        4 => Some(GaugeType::ExHard),
+        5 => Some(GaugeType::Hazard),
+        _ => None,
+    }
+}
+
+//LEB128, input deltas are mostly small so they usually take one or two bytes
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+struct ByteReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8], ReplayError> {
+        let bytes = self.data.get(self.pos .. self.pos + count).ok_or(ReplayError::InvalidFormat)?;
+        self.pos += count;
+        Ok(bytes)
+    }
+    fn byte(&mut self) -> Result<u8, ReplayError> {
+        Ok(self.bytes(1)?[0])
+    }
+    fn varint(&mut self) -> Result<u64, ReplayError> {
+        let mut value = 0u64;
+        for shift in (0 .. 64).step_by(7) {
+            let byte = self.byte()?;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 { return Ok(value); }
+        }
+        Err(ReplayError::InvalidFormat)
+    }
+}
+
+impl Replay {
+    pub fn new(chart_hash: [u8; 16], seed: u64, play_style: PlayStyle, lane_options: [LaneOption; 2], gauge_type: GaugeType) -> Self {
+        Self {
+            chart_hash,
+            seed,
+            play_style,
+            lane_options,
+            gauge_type,
+            inputs: Vec::new(),
+        }
+    }
+    //Inputs have to be recorded in chronological order
+    pub fn record(&mut self, event: KeyEvent) {
+        let last = self.inputs.last().map(|e| e.time).unwrap_or(0.0);
+        let time = quantize(event.time.max(last)) as f64 / 1_000_000.0;
+        self.inputs.push(KeyEvent { time, ..event });
+    }
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(40 + self.inputs.len() * 3);
+        out.extend_from_slice(REPLAY_MAGIC);
+        out.push(REPLAY_VERSION);
+        out.extend_from_slice(&self.chart_hash);
+        out.extend_from_slice(&self.seed.to_le_bytes());
+        out.push(self.play_style.code());
+        out.extend(self.lane_options.iter().map(|o| o.code()));
+        out.push(gauge_code(self.gauge_type));
+        out.extend_from_slice(&(self.inputs.len() as u32).to_le_bytes());
+        let mut last = 0;
+        for event in &self.inputs {
+            let time = quantize(event.time).max(last);
+            write_varint(&mut out, time - last);
+            write_varint(&mut out, ((event.lane as u64) << 1) | event.pressed as u64);
+            last = time;
+        }
+        out
+    }
+    pub fn from_bytes(data: &[u8]) -> Result<Self, ReplayError> {
+        let mut reader = ByteReader { data, pos: 0 };
+        if reader.bytes(4)? != REPLAY_MAGIC { return Err(ReplayError::InvalidFormat); }
+        if reader.byte()? != REPLAY_VERSION { return Err(ReplayError::UnsupportedVersion); }
+        let mut chart_hash = [0; 16];
+        chart_hash.copy_from_slice(reader.bytes(16)?);
+        let mut seed = [0; 8];
+        seed.copy_from_slice(reader.bytes(8)?);
+        let play_style = PlayStyle::from_code(reader.byte()?).ok_or(ReplayError::InvalidFormat)?;
+        let mut lane_options = [LaneOption::Off; 2];
+        for option in lane_options.iter_mut() {
+            *option = LaneOption::from_code(reader.byte()?).ok_or(ReplayError::InvalidFormat)?;
+        }
+        let gauge_type = gauge_from_code(reader.byte()?).ok_or(ReplayError::InvalidFormat)?;
+        let mut count = [0; 4];
+        count.copy_from_slice(reader.bytes(4)?);
+        let count = u32::from_le_bytes(count) as usize;
+        let mut inputs = Vec::with_capacity(count.min(data.len()));
+        let mut time = 0u64;
+        for _ in 0 .. count {
+            time = time.checked_add(reader.varint()?).ok_or(ReplayError::InvalidFormat)?;
+            let key = reader.varint()?;
+            let lane = u32::try_from(key >> 1).map_err(|_| ReplayError::InvalidFormat)?;
+            inputs.push(KeyEvent { time: time as f64 / 1_000_000.0, lane, pressed: key & 1 != 0 });
+        }
+        if reader.pos != data.len() { return Err(ReplayError::InvalidFormat); }
+        Ok(Self {
+            chart_hash,
+            seed: u64::from_le_bytes(seed),
+            play_style,
+            lane_options,
+            gauge_type,
+            inputs,
+        })
+    }
+    //Whether the replay was recorded on this chart file
+    pub fn matches_chart(&self, bms: &ImportedBMS) -> bool {
+        self.chart_hash == bms.md5
+    }
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), ReplayError> {
+        writer.write_all(&self.to_bytes()).map_err(|_| ReplayError::ErrorWritingFile)
+    }
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Self, ReplayError> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).map_err(|_| ReplayError::ErrorReadingFile)?;
+        Self::from_bytes(&data)
+    }
+    pub fn save(&self, path: &Path) -> Result<(), ReplayError> {
+        let mut file = std::fs::File::create(path).map_err(|_| ReplayError::CouldntOpenFile)?;
+        self.write_to(&mut file)
+    }
+    pub fn load(path: &Path) -> Result<Self, ReplayError> {
+        let mut file = std::fs::File::open(path).map_err(|_| ReplayError::CouldntOpenFile)?;
+        Self::read_from(&mut file)
+    }
+    //Re-judges the recorded input against the chart, shuffled the same way it was played
+    pub fn play(&self, bms: &ImportedBMS, cbms: &CBMS) -> ReplayResult {
+        let mut notes = cbms.playable_notes(&bms.timing, &bms.stops);
+        apply_lane_options(&mut notes, self.lane_options, self.play_style, self.seed);
+        let results = judge_inputs(notes, JudgeWindows::for_chart(bms), &self.inputs);
+        let mut gauge = Gauge::for_chart(self.gauge_type, bms, cbms);
+        gauge.apply_all(&results);
+        ReplayResult {
+            score: Score::from_chart_results(cbms, &results),
+            results,
+            gauge,
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_replay_round_trip() {
+    let mut replay = Replay::new([7; 16], 0xdead_beef, PlayStyle::Double7, [LaneOption::Mirror, LaneOption::Off], GaugeType::Hard);
+    replay.record(KeyEvent { time: 0.0000004, lane: 0, pressed: true });
+    replay.record(KeyEvent { time: 0.25, lane: 17, pressed: false });
+    replay.record(KeyEvent { time: 300.123456789, lane: 7, pressed: true });
+    replay.record(KeyEvent { time: 301.0, lane: 200, pressed: true });
+    assert_eq!(replay.inputs[0].time, 0.0);
+    let bytes = replay.to_bytes();
+    assert_eq!(Replay::from_bytes(&bytes), Ok(replay));
+    assert_eq!(Replay::from_bytes(&bytes[.. bytes.len() - 1]), Err(ReplayError::InvalidFormat));
+    let mut bad_version = bytes.clone();
+    bad_version[4] = 99;
+    assert_eq!(Replay::from_bytes(&bad_version), Err(ReplayError::UnsupportedVersion));
+}
+
+#[cfg(test)]
+#[test]
+fn test_replay_reproduces_score() {
+    let bms = crate::compiler::import_bms("#BPM 240\n#TOTAL 200\n#00011:01010101\n#00052:0101").unwrap();
+    let cbms = bms.eval_and_compile();
+    //Played as 7 keys, though keys 6 and 7 have no notes
+    let mut replay = Replay::new([0; 16], 1234, PlayStyle::Single7, [LaneOption::SRandom, LaneOption::Off], GaugeType::Normal);
+    let mut notes = cbms.playable_notes(&bms.timing, &bms.stops);
+    apply_lane_options(&mut notes, replay.lane_options, PlayStyle::Single7, replay.seed);
+    for mut event in crate::cbms::player::autoplay_inputs(&notes) {
+        //Hit everything a bit late
+        event.time += 0.03;
+        replay.record(event);
+    }
+    let played = replay.play(&bms, &cbms);
+    let reloaded = Replay::from_bytes(&replay.to_bytes()).unwrap().play(&bms, &cbms);
+    assert_eq!(played.results, reloaded.results);
+    assert_eq!(played.score, reloaded.score);
+    assert_eq!(played.score.great, 6);
+    assert_eq!(reloaded.gauge.value(), played.gauge.value());
+}
@@ -0,0 +1,166 @@
+use crate::cbms::CBMS;
+use crate::judge::{Judgement, JudgeResult};
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum DjLevel {
+    F,
+    E,
+    D,
+    C,
+    B,
+    A,
+    AA,
+    AAA,
+}
+
+//Ordered from worst to best
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum ClearLamp {
+    NoPlay,
+    Failed,
+    AssistClear,
+    EasyClear,
+    Clear,
+    HardClear,
+    ExHardClear,
+    FullCombo,
+    Perfect,
+    Max,
+}
+
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct Score {
+    pub note_count: usize,
+    pub pgreat: usize,
+    pub great: usize,
+    pub good: usize,
+    pub bad: usize,
+    pub poor: usize,
+    pub empty_poor: usize,
+    //Early and late hits, PGREATs aren't counted
+    pub fast: usize,
+    pub slow: usize,
+    pub max_combo: usize,
+}
+
+impl Score {
+    //`note_count` is the number of judged notes in the chart, see `CBMS::playable_note_count`
+    pub fn from_results(results: &[JudgeResult], note_count: usize) -> Self {
+        let mut score = Score { note_count, ..Default::default() };
+        let mut combo = 0;
+        for result in results {
+            match result.judgement {
+                Judgement::PGreat => score.pgreat += 1,
+                Judgement::Great => score.great += 1,
+                Judgement::Good => score.good += 1,
+                Judgement::Bad => score.bad += 1,
+                Judgement::Poor => score.poor += 1,
+                Judgement::EmptyPoor => score.empty_poor += 1,
+            }
+            match result.judgement {
+                Judgement::PGreat | Judgement::Great | Judgement::Good => combo += 1,
+                Judgement::Bad | Judgement::Poor => combo = 0,
+                //Empty POORs don't break combo
+                Judgement::EmptyPoor => (),
+            }
+            score.max_combo = score.max_combo.max(combo);
+            if result.judgement != Judgement::PGreat {
+                match result.offset {
+                    Some(offset) if offset < 0.0 => score.fast += 1,
+                    Some(offset) if offset > 0.0 => score.slow += 1,
+                    _ => (),
+                }
+            }
+        }
+        score
+    }
+    pub fn from_chart_results(cbms: &CBMS, results: &[JudgeResult]) -> Self {
+        Self::from_results(results, cbms.playable_note_count())
+    }
+    pub fn ex_score(&self) -> usize {
+        self.pgreat * 2 + self.great
+    }
+    pub fn max_ex_score(&self) -> usize {
+        self.note_count * 2
+    }
+    pub fn score_rate(&self) -> f64 {
+        if self.note_count == 0 { return 0.0; }
+        self.ex_score() as f64 / self.max_ex_score() as f64
+    }
+    //Break count, empty POORs included
+    pub fn bp(&self) -> usize {
+        self.bad + self.poor + self.empty_poor
+    }
+    //Notes that broke combo
+    pub fn miss_count(&self) -> usize {
+        self.bad + self.poor
+    }
+    pub fn judged_notes(&self) -> usize {
+        self.pgreat + self.great + self.good + self.bad + self.poor
+    }
+    //DJ levels are spaced by ninths of the max EX score
+    pub fn dj_level(&self) -> DjLevel {
+        let ninths = (self.ex_score() * 9).checked_div(self.max_ex_score()).unwrap_or(0);
+        match ninths {
+            0 ..= 1 => DjLevel::F,
+            2 => DjLevel::E,
+            3 => DjLevel::D,
+            4 => DjLevel::C,
+            5 => DjLevel::B,
+            6 => DjLevel::A,
+            7 => DjLevel::AA,
+            _ => DjLevel::AAA,
+        }
+    }
+    //Upgrades the lamp earned with the gauge to FULL COMBO, PERFECT or MAX when the play deserves it
+    pub fn clear_lamp(&self, gauge_lamp: ClearLamp) -> ClearLamp {
+        if gauge_lamp <= ClearLamp::Failed || self.note_count == 0 || self.judged_notes() < self.note_count || self.miss_count() > 0 {
+            return gauge_lamp;
+        }
+        if self.pgreat == self.note_count {
+            ClearLamp::Max
+        } else if self.good == 0 {
+            ClearLamp::Perfect
+        } else {
+            ClearLamp::FullCombo
+        }
+    }
+}
+
+#[cfg(test)]
+fn test_result(judgement: Judgement, offset: f64) -> JudgeResult {
+    JudgeResult { time: 0.0, lane: 0, note: None, offset: Some(offset), judgement }
+}
+
+#[cfg(test)]
+#[test]
+fn test_score_from_results() {
+    let results = [
+        test_result(Judgement::PGreat, 0.0),
+        test_result(Judgement::Great, -0.03),
+        test_result(Judgement::EmptyPoor, 0.0),
+        test_result(Judgement::Good, 0.08),
+        test_result(Judgement::Poor, 0.0),
+        test_result(Judgement::PGreat, 0.01),
+    ];
+    let score = Score::from_results(&results, 5);
+    assert_eq!(score.ex_score(), 5);
+    assert_eq!(score.max_ex_score(), 10);
+    assert_eq!(score.max_combo, 3);
+    assert_eq!((score.bp(), score.miss_count()), (2, 1));
+    assert_eq!((score.fast, score.slow), (1, 1));
+    assert_eq!(score.dj_level(), DjLevel::C);
+    assert_eq!(score.clear_lamp(ClearLamp::HardClear), ClearLamp::HardClear);
+}
+
+#[cfg(test)]
+#[test]
+fn test_score_full_combo_lamps() {
+    let results = [test_result(Judgement::PGreat, 0.0), test_result(Judgement::Great, 0.03)];
+    let score = Score::from_results(&results, 2);
+    assert_eq!(score.dj_level(), DjLevel::A);
+    assert_eq!(score.clear_lamp(ClearLamp::EasyClear), ClearLamp::Perfect);
+    assert_eq!(score.clear_lamp(ClearLamp::Failed), ClearLamp::Failed);
+    let score = Score::from_results(&results[.. 1], 1);
+    assert_eq!(score.clear_lamp(ClearLamp::Clear), ClearLamp::Max);
+}
@@ -0,0 +1,334 @@
+use crate::cbms::{LANES_PER_SIDE, NoteKind, PlayableNote};
+
+use rand::{RngExt, SeedableRng};
+use rand::rngs::Xoshiro256PlusPlus;
+use rand::seq::SliceRandom;
+
+//Notes closer than this on one lane are considered a jack by H-RANDOM and ALL-SCRATCH
+pub const JACK_THRESHOLD: f64 = 0.1;
+
+//Lane of the turntable, relative to the side
+const SCRATCH: u32 = 5;
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Default)]
+pub enum LaneOption {
+    #[default]
+    Off,
+    Mirror,
+    //One permutation of the keys for the whole chart
+    Random,
+    //Keys rotated by a random amount, possibly mirrored
+    RRandom,
+    //Every note gets a random key
+    SRandom,
+    //S-RANDOM that doesn't reuse the keys of the previous chord
+    SRandomNoJack,
+    //S-RANDOM that avoids keys hit less than `JACK_THRESHOLD` ago
+    HRandom,
+    //Moves as many notes as possible to the scratch
+    AllScratch,
+}
+
+impl LaneOption {
+    pub fn code(&self) -> u8 {
+        match self {
+            LaneOption::Off => 0,
+            LaneOption::Mirror => 1,
+            LaneOption::Random => 2,
+            LaneOption::RRandom => 3,
+            LaneOption::SRandom => 4,
+            LaneOption::SRandomNoJack => 5,
+            LaneOption::HRandom => 6,
+            LaneOption::AllScratch => 7,
+        }
+    }
+    pub fn from_code(code: u8) -> Option<Self> {
+        match code {
+            0 => Some(LaneOption::Off),
+            1 => Some(LaneOption::Mirror),
+            2 => Some(LaneOption::Random),
+            3 => Some(LaneOption::RRandom),
+            4 => Some(LaneOption::SRandom),
+            5 => Some(LaneOption::SRandomNoJack),
+            6 => Some(LaneOption::HRandom),
+            7 => Some(LaneOption::AllScratch),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum PlayStyle {
+    Single5,
+    Single7,
+    Double5,
+    Double7,
+}
+
+impl PlayStyle {
+    //Double when the 2P side has notes, 7 keys when keys 6 or 7 have notes
+    pub fn detect(notes: &[PlayableNote]) -> Self {
+        let double = notes.iter().any(|n| n.lane >= LANES_PER_SIDE);
+        let seven = notes.iter().any(|n| n.lane % LANES_PER_SIDE >= 7);
+        match (double, seven) {
+            (false, false) => PlayStyle::Single5,
+            (false, true) => PlayStyle::Single7,
+            (true, false) => PlayStyle::Double5,
+            (true, true) => PlayStyle::Double7,
+        }
+    }
+    pub fn sides(&self) -> u32 {
+        match self {
+            PlayStyle::Single5 | PlayStyle::Single7 => 1,
+            PlayStyle::Double5 | PlayStyle::Double7 => 2,
+        }
+    }
+    //Key lanes of one side from left to right, the scratch not included
+    pub fn key_lanes(&self, side: u32) -> Vec<u32> {
+        let keys: &[u32] = match self {
+            PlayStyle::Single5 | PlayStyle::Double5 => &[0, 1, 2, 3, 4],
+            PlayStyle::Single7 | PlayStyle::Double7 => &[0, 1, 2, 3, 4, 7, 8],
+        };
+        keys.iter().map(|k| side * LANES_PER_SIDE + k).collect()
+    }
+    pub fn scratch_lane(&self, side: u32) -> u32 {
+        side * LANES_PER_SIDE + SCRATCH
+    }
+    pub fn code(&self) -> u8 {
+        match self {
+            PlayStyle::Single5 => 0,
+            PlayStyle::Single7 => 1,
+            PlayStyle::Double5 => 2,
+            PlayStyle::Double7 => 3,
+        }
+    }
+    pub fn from_code(code: u8) -> Option<Self> {
+        match code {
+            0 => Some(PlayStyle::Single5),
+            1 => Some(PlayStyle::Single7),
+            2 => Some(PlayStyle::Double5),
+            3 => Some(PlayStyle::Double7),
+            _ => None,
+        }
+    }
+}
+
+//Rearranges the notes of each side with its option. The same seed always gives the same chart.
+pub fn apply_lane_options(notes: &mut [PlayableNote], options: [LaneOption; 2], style: PlayStyle, seed: u64) {
+    let mut rng = Xoshiro256PlusPlus::seed_from_u64(seed);
+    for side in 0 .. style.sides() {
+        let keys = style.key_lanes(side);
+        let scratch = style.scratch_lane(side);
+        match options[side as usize] {
+            LaneOption::Off => (),
+            LaneOption::Mirror => {
+                let mut target = keys.clone();
+                target.reverse();
+                permute(notes, &keys, &target);
+            },
+            LaneOption::Random => {
+                let mut target = keys.clone();
+                target.shuffle(&mut rng);
+                permute(notes, &keys, &target);
+            },
+            LaneOption::RRandom => {
+                let mut target = keys.clone();
+                target.rotate_left(rng.random_range(1 .. keys.len()));
+                if rng.random_bool(0.5) {
+                    target.reverse();
+                }
+                permute(notes, &keys, &target);
+            },
+            option => per_note(notes, &keys, scratch, option, &mut rng),
+        }
+    }
+}
+
+fn permute(notes: &mut [PlayableNote], keys: &[u32], target: &[u32]) {
+    for note in notes.iter_mut() {
+        if let Some(idx) = keys.iter().position(|&k| k == note.lane) {
+            note.lane = target[idx];
+        }
+    }
+}
+
+//Lane state while notes are placed one chord at a time
+struct PlacementState {
+    //Original lane of the long note holding each lane
+    held_by: Vec<Option<u32>>,
+    last_hit: Vec<f64>,
+    previous_chord: Vec<u32>,
+}
+
+fn per_note(notes: &mut [PlayableNote], keys: &[u32], scratch: u32, option: LaneOption, rng: &mut Xoshiro256PlusPlus) {
+    let lanes = 2 * LANES_PER_SIDE as usize;
+    let mut state = PlacementState {
+        held_by: vec![None; lanes],
+        last_hit: vec![f64::NEG_INFINITY; lanes],
+        previous_chord: Vec::new(),
+    };
+    let side: Vec<usize> = (0 .. notes.len())
+        .filter(|&idx| keys.contains(&notes[idx].lane) || notes[idx].lane == scratch)
+        .collect();
+    let mut beg = 0;
+    while beg < side.len() {
+        let time = notes[side[beg]].time;
+        let end = beg + side[beg ..].iter().take_while(|&&idx| notes[idx].time == time).count();
+        place_chord(notes, &side[beg .. end], keys, scratch, option, &mut state, rng);
+        beg = end;
+    }
+}
+
+fn place_chord(notes: &mut [PlayableNote], chord: &[usize], keys: &[u32], scratch: u32, option: LaneOption, state: &mut PlacementState, rng: &mut Xoshiro256PlusPlus) {
+    let time = notes[chord[0]].time;
+    //Long note ends follow their starts, which frees the lanes
+    for &idx in chord {
+        if notes[idx].kind != NoteKind::LongEnd { continue; }
+        if let Some(lane) = state.held_by.iter().position(|&h| h == Some(notes[idx].lane)) {
+            state.held_by[lane] = None;
+            notes[idx].lane = lane as u32;
+        }
+    }
+    let free = |lane: u32, used: &[u32], state: &PlacementState| state.held_by[lane as usize].is_none() && !used.contains(&lane);
+    let on_scratch: Vec<usize> = chord.iter().cloned()
+        .filter(|&idx| notes[idx].lane == scratch && notes[idx].kind != NoteKind::LongEnd)
+        .collect();
+    let mut used = Vec::new();
+    //Scratch notes stay where they are, unless ALL-SCRATCH put a long note there
+    for &idx in &on_scratch {
+        let lane = if free(scratch, &used, state) {
+            scratch
+        } else {
+            pick(keys.iter().cloned().filter(|&k| free(k, &used, state)).collect(), scratch, rng)
+        };
+        notes[idx].lane = lane;
+        used.push(lane);
+        if notes[idx].kind == NoteKind::LongStart {
+            state.held_by[lane as usize] = Some(scratch);
+        }
+    }
+    for &idx in chord {
+        let original = notes[idx].lane;
+        if notes[idx].kind == NoteKind::LongEnd || on_scratch.contains(&idx) { continue; }
+        let lane = if option == LaneOption::AllScratch {
+            if free(scratch, &used, state) && time - state.last_hit[scratch as usize] >= JACK_THRESHOLD {
+                scratch
+            } else if free(original, &used, state) {
+                original
+            } else {
+                pick(keys.iter().cloned().filter(|&k| free(k, &used, state)).collect(), original, rng)
+            }
+        } else {
+            let candidates: Vec<u32> = keys.iter().cloned().filter(|&k| free(k, &used, state)).collect();
+            let preferred: Vec<u32> = candidates.iter().cloned().filter(|&k| match option {
+                LaneOption::SRandomNoJack => !state.previous_chord.contains(&k),
+                LaneOption::HRandom => time - state.last_hit[k as usize] >= JACK_THRESHOLD,
+                _ => true,
+            }).collect();
+            if preferred.is_empty() { pick(candidates, original, rng) } else { pick(preferred, original, rng) }
+        };
+        notes[idx].lane = lane;
+        used.push(lane);
+        if notes[idx].kind == NoteKind::LongStart {
+            state.held_by[lane as usize] = Some(original);
+        }
+    }
+    for &lane in &used {
+        state.last_hit[lane as usize] = time;
+    }
+    state.previous_chord = used;
+}
+
+fn pick(candidates: Vec<u32>, fallback: u32, rng: &mut Xoshiro256PlusPlus) -> u32 {
+    if candidates.is_empty() { return fallback; }
+    candidates[rng.random_range(0 .. candidates.len())]
+}
+
+#[cfg(test)]
+fn test_notes(raw_bms: &str) -> Vec<PlayableNote> {
+    let bms = crate::compiler::import_bms(raw_bms).unwrap();
+    bms.eval_and_compile().playable_notes(&bms.timing, &bms.stops)
+}
+
+#[cfg(test)]
+fn lanes(notes: &[PlayableNote]) -> Vec<u32> {
+    notes.iter().map(|n| n.lane).collect()
+}
+
+#[cfg(test)]
+#[test]
+fn test_fixed_lane_options() {
+    //Keys 1, 2 and 7 and the scratch
+    let notes = test_notes("#BPM 240\n#00011:01000000\n#00012:00010000\n#00019:00000100\n#00016:00000001");
+    assert_eq!(PlayStyle::detect(&notes), PlayStyle::Single7);
+    let mut mirror = notes.clone();
+    apply_lane_options(&mut mirror, [LaneOption::Mirror, LaneOption::Off], PlayStyle::Single7, 0);
+    assert_eq!(lanes(&mirror), vec![8, 7, 0, 5]);
+    for option in [LaneOption::Random, LaneOption::RRandom] {
+        let mut a = notes.clone();
+        let mut b = notes.clone();
+        apply_lane_options(&mut a, [option, LaneOption::Off], PlayStyle::Single7, 42);
+        apply_lane_options(&mut b, [option, LaneOption::Off], PlayStyle::Single7, 42);
+        assert_eq!(lanes(&a), lanes(&b));
+        assert_eq!(a[3].lane, 5);
+        let mut keys = lanes(&a)[.. 3].to_vec();
+        keys.sort();
+        keys.dedup();
+        assert_eq!(keys.len(), 3);
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_per_note_lane_options() {
+    //Sixteenth notes on key 1 with a long note on key 2 held through them
+    let notes = test_notes("#BPM 240\n#00011:01010101010101010101010101010101\n#00052:0100000000000001");
+    for seed in 0 .. 20 {
+        for option in [LaneOption::SRandomNoJack, LaneOption::HRandom] {
+            let mut shuffled = notes.clone();
+            apply_lane_options(&mut shuffled, [option, LaneOption::Off], PlayStyle::Single7, seed);
+            let starts: Vec<&PlayableNote> = shuffled.iter().filter(|n| n.kind == NoteKind::LongStart).collect();
+            let ends: Vec<&PlayableNote> = shuffled.iter().filter(|n| n.kind == NoteKind::LongEnd).collect();
+            assert_eq!(starts[0].lane, ends[0].lane);
+            let taps: Vec<&PlayableNote> = shuffled.iter().filter(|n| n.kind == NoteKind::Normal).collect();
+            assert!(taps.iter().all(|n| n.lane != starts[0].lane || n.time >= ends[0].time));
+            assert!(taps.windows(2).all(|w| w[0].lane != w[1].lane));
+        }
+    }
+    //Chords of keys 1 and 2 every quarter, only one note of each fits on the scratch
+    let mut scratched = test_notes("#BPM 240\n#00011:01010101\n#00012:01010101");
+    apply_lane_options(&mut scratched, [LaneOption::AllScratch, LaneOption::Off], PlayStyle::Single5, 0);
+    assert_eq!(lanes(&scratched).iter().filter(|&&l| l == 5).count(), 4);
+}
+
+#[cfg(test)]
+#[test]
+fn test_all_scratch_long_notes() {
+    //A scratch long note with a key 1 note in the middle of it
+    let mut notes = test_notes("#BPM 240\n#00056:0100000000000001\n#00011:00000100");
+    apply_lane_options(&mut notes, [LaneOption::AllScratch, LaneOption::Off], PlayStyle::Single5, 0);
+    let on_lane = |notes: &[PlayableNote], kind: NoteKind| notes.iter().find(|n| n.kind == kind).unwrap().lane;
+    assert_eq!((on_lane(&notes, NoteKind::LongStart), on_lane(&notes, NoteKind::LongEnd), on_lane(&notes, NoteKind::Normal)), (5, 5, 0));
+    //A key 2 long note moved to the scratch pushes the scratch note under it onto a key
+    for seed in 0 .. 10 {
+        let mut notes = test_notes("#BPM 240\n#00052:0100000000000001\n#00016:00000100");
+        apply_lane_options(&mut notes, [LaneOption::AllScratch, LaneOption::Off], PlayStyle::Single5, seed);
+        assert_eq!((on_lane(&notes, NoteKind::LongStart), on_lane(&notes, NoteKind::LongEnd)), (5, 5));
+        assert!(PlayStyle::Single5.key_lanes(0).contains(&on_lane(&notes, NoteKind::Normal)));
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_lane_options_known_seed() {
+    //Replays only store the seed, so these layouts must never change
+    let notes = test_notes("#BPM 240\n#00011:0101010101010101\n#00013:0001000100010001\n#00019:0000010000000001");
+    let shuffled = |option: LaneOption| {
+        let mut shuffled = notes.clone();
+        apply_lane_options(&mut shuffled, [option, LaneOption::Off], PlayStyle::Single7, 1234);
+        lanes(&shuffled)
+    };
+    assert_eq!(shuffled(LaneOption::Random), vec![7, 7, 4, 7, 8, 7, 4, 7, 7, 4, 7, 7, 4, 8]);
+    assert_eq!(shuffled(LaneOption::RRandom), vec![4, 4, 2, 4, 7, 4, 2, 4, 4, 2, 4, 4, 2, 7]);
+    assert_eq!(shuffled(LaneOption::SRandom), vec![7, 2, 8, 0, 7, 2, 1, 8, 8, 0, 8, 1, 0, 2]);
+}
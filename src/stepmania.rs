@@ -0,0 +1,330 @@
+use crate::cbms::{BGM_CHANNEL, MINE_CHANNEL};
+use crate::compiler::{ChartBuilder, ImportedBMS, SILENT_KEYSOUND, beat_position};
+use crate::hash;
+
+use std::fs::File;
+use std::io::Read;
+
+//Object value of mines, in the half percents BMS counts mine damage in
+pub const MINE_DAMAGE: u32 = 16;
+const MUSIC_KEYSOUND: u32 = 1;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SmImportError {
+    CouldntOpenFile,
+    ErrorReadingFile,
+    InvalidFormat,
+}
+
+//One difficulty of a simfile
+#[derive(Debug)]
+pub struct SmChart {
+    //dance-single, dance-double...
+    pub steps_type: String,
+    pub difficulty: String,
+    pub meter: u32,
+    pub bms: ImportedBMS,
+}
+
+#[derive(Clone, PartialEq, Debug, Default)]
+struct SmTiming {
+    //Beat and BPM
+    bpms: Vec<(f64, f64)>,
+    //Beat and length in seconds
+    stops: Vec<(f64, f64)>,
+    //Seconds from beat 0 to the start of the music
+    offset: f64,
+}
+
+#[derive(Clone, PartialEq, Debug, Default)]
+struct SmNotes {
+    steps_type: String,
+    difficulty: String,
+    meter: u32,
+    notes: String,
+    //.ssc charts can have timing of their own
+    timing: Option<SmTiming>,
+}
+
+//Note channels of the panels from left to right, None for games that aren't supported
+pub fn panel_channels(steps_type: &str) -> Option<&'static [u32]> {
+    match steps_type.to_ascii_lowercase().as_str() {
+        "dance-single" => Some(&[11, 12, 13, 14]),
+        "dance-solo" => Some(&[11, 12, 13, 14, 15, 18]),
+        "dance-double" => Some(&[11, 12, 13, 14, 21, 22, 23, 24]),
+        _ => None,
+    }
+}
+
+//#TAG:value; pairs in file order, with comments left out
+fn tags(raw_sm: &str) -> Vec<(String, String)> {
+    let text = raw_sm.lines()
+        .map(|line| line.find("//").map_or(line, |idx| &line[.. idx]))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let mut tags = Vec::new();
+    let mut rest = text.as_str();
+    while let Some(start) = rest.find('#') {
+        rest = &rest[start + 1 ..];
+        let colon = match rest.find(':') {
+            Some(colon) => colon,
+            None => break,
+        };
+        //Some files forget the semicolon, the next tag ends the value then
+        let end = match (rest.find(';'), rest.find("\n#")) {
+            (Some(semicolon), Some(next)) => semicolon.min(next),
+            (Some(end), None) | (None, Some(end)) => end,
+            (None, None) => rest.len(),
+        };
+        if colon < end {
+            tags.push((rest[.. colon].trim().to_ascii_uppercase(), rest[colon + 1 .. end].trim().to_string()));
+        }
+        rest = &rest[end ..];
+    }
+    tags
+}
+
+//Lists like 0.000=120.000,64.000=240.000. Pairs that aren't finite numbers are left out.
+fn beat_pairs(value: &str) -> Vec<(f64, f64)> {
+    let mut pairs: Vec<(f64, f64)> = value.split(',')
+        .filter_map(|pair| {
+            let (beat, value) = pair.split_once('=')?;
+            Some((beat.trim().parse().ok()?, value.trim().parse().ok()?))
+        })
+        .filter(|(beat, value): &(f64, f64)| beat.is_finite() && value.is_finite())
+        .collect();
+    pairs.sort_by(|a, b| a.0.total_cmp(&b.0));
+    pairs
+}
+
+fn apply_timing_tag(timing: &mut SmTiming, tag: &str, value: &str) {
+    match tag {
+        "BPMS" => timing.bpms = beat_pairs(value),
+        "STOPS" | "FREEZES" => timing.stops = beat_pairs(value),
+        "OFFSET" => timing.offset = value.parse().ok().filter(|offset: &f64| offset.is_finite()).unwrap_or(0.0),
+        _ => (),
+    }
+}
+
+//Beat the chart is at `time` seconds after beat 0
+fn beat_at_time(bpms: &[(f64, f64)], stops: &[(f64, f64)], time: f64) -> f64 {
+    let (mut beat, mut elapsed, mut bpm) = (0.0, 0.0, bpms[0].1);
+    if time <= 0.0 { return time * bpm / 60.0; }
+    //BPM changes go before stops on the same beat
+    let mut events: Vec<(f64, Option<f64>, f64)> = bpms[1 ..].iter().map(|&(at, bpm)| (at, Some(bpm), 0.0))
+        .chain(stops.iter().map(|&(at, length)| (at, None, length)))
+        .collect();
+    events.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.is_none().cmp(&b.1.is_none())));
+    for (at, change, stop) in events {
+        let reached = elapsed + (at - beat) * 60.0 / bpm;
+        if reached >= time { break; }
+        elapsed = reached;
+        beat = at;
+        if let Some(change) = change { bpm = change; }
+        elapsed += stop;
+        if elapsed >= time { return beat; }
+    }
+    beat + (time - elapsed) * bpm / 60.0
+}
+
+fn build_chart(title: &str, music: Option<&str>, notes: &SmNotes, timing: &SmTiming, panels: &[u32]) -> Result<ImportedBMS, SmImportError> {
+    //Negative BPMs are warps, which can't be expressed here and are left out
+    let bpms: Vec<(f64, f64)> = timing.bpms.iter().copied().filter(|&(_, bpm)| bpm > 0.0).collect();
+    if bpms.is_empty() { return Err(SmImportError::InvalidFormat); }
+    let stops: Vec<(f64, f64)> = timing.stops.iter().copied().filter(|&(beat, _)| beat >= 0.0).collect();
+    //Music starting before beat 0 gets whole measures put in front of the chart
+    let measure_length = 240.0 / bpms[0].1;
+    let lead_in = if timing.offset < 0.0 { (-timing.offset / measure_length).ceil() as u32 } else { 0 };
+    let shift = 4.0 * lead_in as f64;
+    let mut builder = ChartBuilder::new(bpms[0].1 as f32);
+    builder.set_title(title);
+    builder.set_playlevel(notes.meter);
+    for &(beat, bpm) in bpms[1 ..].iter().filter(|(beat, _)| *beat > 0.0) {
+        let (measure, num, den) = beat_position(beat + shift);
+        builder.add_bpm_change(measure, num, den, bpm as f32);
+    }
+    for &(beat, length) in &stops {
+        let bpm = bpms.iter().take_while(|(at, _)| *at <= beat).last().map_or(bpms[0].1, |(_, bpm)| *bpm);
+        let (measure, num, den) = beat_position(beat + shift);
+        builder.add_stop(measure, num, den, length * bpm / 240.0 * 192.0);
+    }
+    if let Some(music) = music {
+        builder.set_keysound(MUSIC_KEYSOUND, music);
+        let (measure, num, den) = beat_position(beat_at_time(&bpms, &stops, timing.offset) + shift);
+        builder.add_object(measure, num, den, BGM_CHANNEL, MUSIC_KEYSOUND);
+    }
+    let mut hold_heads: Vec<Option<(u32, u32, u32)>> = vec![None; panels.len()];
+    for (measure, measure_notes) in notes.notes.split(',').enumerate() {
+        let rows: Vec<&str> = measure_notes.lines().map(str::trim).filter(|row| !row.is_empty()).collect();
+        let measure = measure as u32 + lead_in;
+        for (row_idx, row) in rows.iter().enumerate() {
+            let pos = (measure, row_idx as u32, rows.len() as u32);
+            for (panel, note) in row.chars().take(panels.len()).enumerate() {
+                let channel = panels[panel];
+                match note {
+                    //Lifts are played like taps
+                    '1' | 'L' => builder.add_object(pos.0, pos.1, pos.2, channel, SILENT_KEYSOUND),
+                    //Holds and rolls
+                    '2' | '4' => hold_heads[panel] = Some(pos),
+                    '3' => if let Some(head) = hold_heads[panel].take() {
+                        //11 to 51, the long note channel of the same lane
+                        builder.add_object(head.0, head.1, head.2, channel + 40, SILENT_KEYSOUND);
+                        builder.add_object(pos.0, pos.1, pos.2, channel + 40, SILENT_KEYSOUND);
+                    },
+                    'M' => builder.add_object(pos.0, pos.1, pos.2, channel - 11 + MINE_CHANNEL, MINE_DAMAGE),
+                    _ => (),
+                }
+            }
+        }
+    }
+    //Holds that never end are played as taps
+    for (panel, head) in hold_heads.into_iter().enumerate() {
+        if let Some(head) = head {
+            builder.add_object(head.0, head.1, head.2, panels[panel], SILENT_KEYSOUND);
+        }
+    }
+    Ok(builder.build())
+}
+
+pub fn import_sm_from_file(path: &str) -> Result<Vec<SmChart>, SmImportError> {
+    let mut file = File::open(path)
+        .map_err(|_| SmImportError::CouldntOpenFile)?;
+    let mut raw_sm = Vec::new();
+    file.read_to_end(&mut raw_sm)
+        .map_err(|_| SmImportError::ErrorReadingFile)?;
+    let mut charts = import_sm(&String::from_utf8_lossy(&raw_sm))?;
+    //Every chart of the file shares its hashes
+    let (md5, sha256) = (hash::md5(&raw_sm), hash::sha256(&raw_sm));
+    for chart in &mut charts {
+        chart.bms.md5 = md5;
+        chart.bms.sha256 = sha256;
+    }
+    Ok(charts)
+}
+
+//Reads both .sm and .ssc files, with one chart per difficulty.
+//Charts for games other than dance-single, dance-solo and dance-double are skipped.
+pub fn import_sm(raw_sm: &str) -> Result<Vec<SmChart>, SmImportError> {
+    let tags = tags(raw_sm);
+    if tags.is_empty() { return Err(SmImportError::InvalidFormat); }
+    let mut title = String::new();
+    let mut music = None;
+    let mut song_timing = SmTiming::default();
+    let mut all_notes = Vec::new();
+    //.ssc charts are a #NOTEDATA tag followed by the tags describing the chart
+    let mut ssc_notes: Option<SmNotes> = None;
+    for (tag, value) in &tags {
+        match (tag.as_str(), ssc_notes.as_mut()) {
+            ("NOTEDATA", _) => {
+                all_notes.extend(ssc_notes.take());
+                ssc_notes = Some(SmNotes::default());
+            },
+            ("STEPSTYPE", Some(notes)) => notes.steps_type = value.clone(),
+            ("DIFFICULTY", Some(notes)) => notes.difficulty = value.clone(),
+            ("METER", Some(notes)) => notes.meter = value.parse().unwrap_or(0),
+            ("NOTES", Some(notes)) => notes.notes = value.clone(),
+            ("BPMS", Some(notes)) | ("STOPS", Some(notes)) | ("FREEZES", Some(notes)) | ("OFFSET", Some(notes)) => {
+                let timing = notes.timing.get_or_insert_with(|| song_timing.clone());
+                apply_timing_tag(timing, tag, value);
+            },
+            ("TITLE", None) => title = value.clone(),
+            ("MUSIC", None) => music = Some(value.clone()).filter(|m| !m.is_empty()),
+            ("BPMS", None) | ("STOPS", None) | ("FREEZES", None) | ("OFFSET", None) => apply_timing_tag(&mut song_timing, tag, value),
+            //type:description:difficulty:meter:radar values:notes
+            ("NOTES", None) => {
+                let fields: Vec<&str> = value.splitn(6, ':').map(str::trim).collect();
+                if let [steps_type, _, difficulty, meter, _, notes] = fields[..] {
+                    all_notes.push(SmNotes {
+                        steps_type: steps_type.to_string(),
+                        difficulty: difficulty.to_string(),
+                        meter: meter.parse().unwrap_or(0),
+                        notes: notes.to_string(),
+                        timing: None,
+                    });
+                }
+            },
+            _ => (),
+        }
+    }
+    all_notes.extend(ssc_notes);
+    let mut charts = Vec::new();
+    for notes in &all_notes {
+        if let Some(panels) = panel_channels(&notes.steps_type) {
+            let timing = notes.timing.as_ref().unwrap_or(&song_timing);
+            charts.push(SmChart {
+                steps_type: notes.steps_type.clone(),
+                difficulty: notes.difficulty.clone(),
+                meter: notes.meter,
+                bms: build_chart(&title, music.as_deref(), notes, timing, panels)?,
+            });
+        }
+    }
+    Ok(charts)
+}
+
+#[cfg(test)]
+#[test]
+fn test_import_sm() {
+    use crate::cbms::NoteKind;
+    let charts = import_sm(concat!(
+        "#TITLE:Song;\n#MUSIC:song.ogg;\n#OFFSET:-0.5;\n",
+        "#BPMS:0.000=120.000,4.000=240.000;\n#STOPS:8.000=0.500;\n",
+        "#NOTES:\n     dance-single:\n     :\n     Easy:\n     3:\n     0,0,0,0,0:\n",
+        "1000\n0100\n0010\n0001\n,// second measure\n2000\n0000\n3M00\n0000\n;\n",
+        "#NOTES:pump-single::Hard:9::10000\n;\n",
+        "#NOTES:dance-double::Hard:8::00000001\n;\n",
+    )).unwrap();
+    assert_eq!(charts.len(), 2);
+    assert_eq!((charts[0].difficulty.as_str(), charts[0].meter, charts[0].bms.playlevel), ("Easy", 3, Some(3)));
+    let bms = &charts[0].bms;
+    assert_eq!(bms.title, "Song");
+    assert_eq!(bms.resource_table[1], "song.ogg");
+    //One measure of lead in, a measure at 120 BPM and the hold at 240 BPM
+    let notes = bms.eval_and_compile().playable_notes(&bms.timing, &bms.stops);
+    let times: Vec<(u32, f64, NoteKind)> = notes.iter().map(|n| (n.lane, n.time, n.kind)).collect();
+    assert_eq!(times, vec![
+        (0, 2.0, NoteKind::Normal),
+        (1, 2.5, NoteKind::Normal),
+        (2, 3.0, NoteKind::Normal),
+        (3, 3.5, NoteKind::Normal),
+        (0, 4.0, NoteKind::LongStart),
+        (0, 4.5, NoteKind::LongEnd),
+    ]);
+    assert_eq!(bms.stops.len(), 1);
+    assert!((bms.stops[0].1 - 0.5).abs() < 1e-6);
+    //The music starts half a second before beat 0
+    let bgm = bms.eval_and_compile().timed_commands(&bms.timing, &bms.stops).into_iter()
+        .find(|tc| matches!(tc.command.kind(), crate::cbms::ChannelKind::Bgm))
+        .unwrap();
+    assert!((bgm.time - 1.5).abs() < 1e-3);
+    assert_eq!(charts[1].steps_type, "dance-double");
+    assert_eq!(charts[1].bms.eval_and_compile().playable_notes(&charts[1].bms.timing, &charts[1].bms.stops)[0].lane, 12);
+}
+
+#[cfg(test)]
+#[test]
+fn test_import_ssc() {
+    let charts = import_sm(concat!(
+        "#VERSION:0.83;\n#TITLE:Song;\n#BPMS:0=150;\n#OFFSET:0;\n",
+        "#NOTEDATA:;\n#STEPSTYPE:dance-solo;\n#DIFFICULTY:Challenge;\n#METER:12;\n#BPMS:0=300;\n#NOTES:\n000001\n;\n",
+        "#NOTEDATA:;\n#STEPSTYPE:dance-single;\n#DIFFICULTY:Beginner;\n#METER:1;\n#NOTES:\n0000\n0000\n0000\n0001\n;\n",
+    )).unwrap();
+    assert_eq!(charts.len(), 2);
+    assert_eq!((charts[0].bms.bpm, charts[1].bms.bpm), (300.0, 150.0));
+    let solo = charts[0].bms.eval_and_compile().playable_notes(&charts[0].bms.timing, &charts[0].bms.stops);
+    assert_eq!(solo[0].lane, 7);
+    let single = charts[1].bms.eval_and_compile().playable_notes(&charts[1].bms.timing, &charts[1].bms.stops);
+    assert_eq!((single[0].lane, single[0].time), (3, 1.2));
+}
+
+#[cfg(test)]
+#[test]
+fn test_import_sm_non_finite_timing() {
+    let charts = import_sm(concat!(
+        "#TITLE:Song;\n#MUSIC:song.ogg;\n#OFFSET:nan;\n#BPMS:nan=120,0=150,4=NaN,8=inf;\n#STOPS:nan=1,4=inf;\n",
+        "#NOTES:dance-single::Easy:1::1000\n;\n",
+    )).unwrap();
+    let bms = &charts[0].bms;
+    assert_eq!((bms.bpm, bms.timing.len(), bms.stops.len()), (150.0, 1, 0));
+    assert_eq!(import_sm("#BPMS:nan=120;\n#NOTES:dance-single::Easy:1::1000\n;\n").unwrap_err(), SmImportError::InvalidFormat);
+}
@@ -0,0 +1,210 @@
+use crate::hash;
+use crate::library::{ChartEntry, Library};
+
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::Path;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum TableError {
+    CouldntOpenFile,
+    InvalidFormat,
+}
+
+//header.json of a difficulty table
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct TableHeader {
+    pub name: String,
+    //Put in front of levels, like ★ for ★12
+    pub symbol: String,
+    //Where data.json is, relative to the header
+    pub data_url: Option<String>,
+    pub level_order: Vec<String>,
+}
+
+//One chart listed in data.json
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct TableEntry {
+    pub md5: Option<[u8; 16]>,
+    pub sha256: Option<[u8; 32]>,
+    pub level: String,
+    pub title: String,
+    pub artist: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct TableJoin<'t, 'l> {
+    pub found: Vec<(&'t TableEntry, &'l ChartEntry)>,
+    pub missing: Vec<&'t TableEntry>,
+}
+
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct DifficultyTable {
+    pub header: TableHeader,
+    pub entries: Vec<TableEntry>,
+}
+
+//Tables write numbers both as strings and as numbers
+fn string_field(object: &Value, key: &str) -> String {
+    match object.get(key) {
+        Some(Value::String(s)) => s.trim().to_string(),
+        Some(Value::Number(n)) => n.to_string(),
+        _ => String::new(),
+    }
+}
+
+fn hash_field<const N: usize>(object: &Value, key: &str) -> Option<[u8; N]> {
+    let bytes = hash::from_hex(object.get(key)?.as_str()?)?;
+    std::convert::TryInto::try_into(bytes).ok()
+}
+
+impl TableHeader {
+    pub fn parse(header_json: &str) -> Result<Self, TableError> {
+        let header: Value = serde_json::from_str(header_json).map_err(|_| TableError::InvalidFormat)?;
+        if !header.is_object() { return Err(TableError::InvalidFormat); }
+        let level_order = match header.get("level_order") {
+            Some(Value::Array(levels)) => levels.iter()
+                .map(|l| match l {
+                    Value::String(s) => s.clone(),
+                    other => other.to_string(),
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        Ok(Self {
+            name: string_field(&header, "name"),
+            symbol: string_field(&header, "symbol"),
+            data_url: header.get("data_url").and_then(Value::as_str).map(str::to_string),
+            level_order,
+        })
+    }
+}
+
+impl DifficultyTable {
+    pub fn parse(header_json: &str, data_json: &str) -> Result<Self, TableError> {
+        let header = TableHeader::parse(header_json)?;
+        let data: Value = serde_json::from_str(data_json).map_err(|_| TableError::InvalidFormat)?;
+        let entries = data.as_array()
+            .ok_or(TableError::InvalidFormat)?
+            .iter()
+            .map(|chart| TableEntry {
+                md5: hash_field(chart, "md5"),
+                sha256: hash_field(chart, "sha256"),
+                level: string_field(chart, "level"),
+                title: string_field(chart, "title"),
+                artist: string_field(chart, "artist"),
+            })
+            .collect();
+        Ok(Self { header, entries })
+    }
+    //Reads the header and the data file it points to, data.json next to the header if it doesn't.
+    //Only local files are read, URLs are reduced to their file name.
+    pub fn load(header_path: &Path) -> Result<Self, TableError> {
+        let header_json = std::fs::read_to_string(header_path).map_err(|_| TableError::CouldntOpenFile)?;
+        let header = TableHeader::parse(&header_json)?;
+        let data_name = header.data_url.as_deref()
+            .and_then(|url| url.rsplit('/').next())
+            .filter(|name| !name.is_empty())
+            .unwrap_or("data.json");
+        let data_path = header_path.parent().unwrap_or_else(|| Path::new("")).join(data_name);
+        let data_json = std::fs::read_to_string(data_path).map_err(|_| TableError::CouldntOpenFile)?;
+        Self::parse(&header_json, &data_json)
+    }
+    //Levels in the table's order. Tables without one get theirs sorted numerically where possible.
+    pub fn levels(&self) -> Vec<String> {
+        if !self.header.level_order.is_empty() {
+            return self.header.level_order.clone();
+        }
+        let mut levels: Vec<String> = Vec::new();
+        for entry in &self.entries {
+            if !levels.contains(&entry.level) {
+                levels.push(entry.level.clone());
+            }
+        }
+        levels.sort_by(|a, b| match (a.parse::<f64>(), b.parse::<f64>()) {
+            (Ok(a), Ok(b)) => a.total_cmp(&b),
+            (Ok(_), Err(_)) => std::cmp::Ordering::Less,
+            (Err(_), Ok(_)) => std::cmp::Ordering::Greater,
+            (Err(_), Err(_)) => a.cmp(b),
+        });
+        levels
+    }
+    pub fn entries_at_level<'t>(&'t self, level: &'t str) -> impl Iterator<Item = &'t TableEntry> {
+        self.entries.iter().filter(move |e| e.level == level)
+    }
+    //SHA-256 is checked first, MD5 for entries that only have that
+    pub fn find_entry(&self, chart: &ChartEntry) -> Option<&TableEntry> {
+        self.entries.iter().find(|e| e.sha256 == Some(chart.sha256))
+            .or_else(|| self.entries.iter().find(|e| e.md5 == Some(chart.md5)))
+    }
+    //Symbol and level, like ★12
+    pub fn level_label(&self, chart: &ChartEntry) -> Option<String> {
+        self.find_entry(chart).map(|e| format!("{}{}", self.header.symbol, e.level))
+    }
+    //Pairs every table entry with the local chart it names, or lists it as missing
+    pub fn join<'t, 'l>(&'t self, library: &'l Library) -> TableJoin<'t, 'l> {
+        //Indexed once, tables and libraries both run into the thousands
+        let mut by_md5 = HashMap::new();
+        let mut by_sha256 = HashMap::new();
+        for chart in library.entries() {
+            by_md5.entry(chart.md5).or_insert(chart);
+            by_sha256.entry(chart.sha256).or_insert(chart);
+        }
+        let mut join = TableJoin { found: Vec::new(), missing: Vec::new() };
+        for entry in &self.entries {
+            let chart = entry.sha256.and_then(|sha256| by_sha256.get(&sha256).copied())
+                .or_else(|| entry.md5.and_then(|md5| by_md5.get(&md5).copied()));
+            match chart {
+                Some(chart) => join.found.push((entry, chart)),
+                None => join.missing.push(entry),
+            }
+        }
+        join
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_parse_difficulty_table() {
+    let header = r#"{"name": "Test table", "symbol": "★", "data_url": "https://example.com/table/score.json"}"#;
+    let data = r#"[
+        {"md5": "d41d8cd98f00b204e9800998ecf8427e", "level": "12", "title": "Empty"},
+        {"sha256": "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad", "level": 3, "title": "abc", "artist": "x"},
+        {"md5": "not a hash", "level": "?", "title": "Broken"}
+    ]"#;
+    let table = DifficultyTable::parse(header, data).unwrap();
+    assert_eq!(table.header.data_url.as_deref(), Some("https://example.com/table/score.json"));
+    assert_eq!(table.entries[0].md5, Some(hash::md5(b"")));
+    assert_eq!((table.entries[1].sha256, table.entries[1].level.as_str()), (Some(hash::sha256(b"abc")), "3"));
+    assert_eq!(table.entries[2].md5, None);
+    assert_eq!(table.levels(), vec!["3", "12", "?"]);
+    let odd_levels = DifficultyTable::parse(header, r#"[{"level": "nan"}, {"level": "inf"}, {"level": "-1"}]"#).unwrap();
+    assert_eq!(odd_levels.levels(), vec!["-1", "inf", "nan"]);
+    assert_eq!(table.entries_at_level("12").count(), 1);
+    assert_eq!(DifficultyTable::parse("[]", data), Err(TableError::InvalidFormat));
+}
+
+#[cfg(test)]
+#[test]
+fn test_join_table_with_library() {
+    let root = std::env::temp_dir().join(format!("mbms-table-test-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    std::fs::create_dir_all(root.join("songs/a")).unwrap();
+    let chart = "#TITLE A\n#BPM 120\n#00111:01\n";
+    std::fs::write(root.join("songs/a/a.bms"), chart).unwrap();
+    std::fs::write(root.join("header.json"), r#"{"name": "T", "symbol": "sl", "data_url": "score.json", "level_order": [0, "1"]}"#).unwrap();
+    std::fs::write(root.join("score.json"), format!(
+        r#"[{{"md5": "{}", "level": "1", "title": "A"}}, {{"md5": "00000000000000000000000000000000", "level": "0", "title": "B"}}]"#,
+        hash::to_hex(&hash::md5(chart.as_bytes())),
+    )).unwrap();
+    let table = DifficultyTable::load(&root.join("header.json")).unwrap();
+    assert_eq!(table.levels(), vec!["0", "1"]);
+    let mut library = Library::new();
+    library.scan(&root.join("songs"));
+    let join = table.join(&library);
+    assert_eq!(join.found.len(), 1);
+    assert_eq!(join.found[0].1.title, "A");
+    assert_eq!(join.missing[0].title, "B");
+    assert_eq!(table.level_label(join.found[0].1).as_deref(), Some("sl1"));
+    std::fs::remove_dir_all(&root).unwrap();
+}